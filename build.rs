@@ -0,0 +1,29 @@
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let mut config = cbindgen::Config::from_root_or_default(&crate_dir);
+    config.after_includes = Some(format!(
+        "\n#define BTLEPLUG_C_VERSION \"{version}\"\n{}",
+        config.after_includes.unwrap_or_default()
+    ));
+    let bindings = cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Unable to generate btleplug_c.h");
+
+    bindings.write_to_file(out_dir.join("btleplug_c.h"));
+
+    println!("cargo:rerun-if-env-changed=BTLEPLUG_C_UPDATE_HEADER");
+    if env::var_os("BTLEPLUG_C_UPDATE_HEADER").is_some() {
+        bindings.write_to_file(PathBuf::from(&crate_dir).join("include/btleplug_c.h"));
+    }
+}
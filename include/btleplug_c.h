@@ -0,0 +1,728 @@
+#ifndef BTLEPLUG_C_H
+#define BTLEPLUG_C_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit by hand. */
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#define BTLEPLUG_C_VERSION "0.1.0"
+
+typedef struct Uuid {
+  uint8_t bytes[16];
+} Uuid;
+
+typedef uint8_t CharPropFlags;
+
+/**
+ * Connection states reported to a peripheral's connection callback
+ */
+typedef enum ConnectionState {
+  CONNECTION_STATE_DISCONNECTED = 0,
+  CONNECTION_STATE_CONNECTING = 1,
+  CONNECTION_STATE_CONNECTED = 2,
+  CONNECTION_STATE_DISCONNECTING = 3,
+} ConnectionState;
+
+/**
+ * Identifies which member of `ModuleEventData` an event carries
+ */
+typedef enum EventKind {
+  /**
+   * No event arrived before the timeout
+   */
+  EVENT_KIND_NONE = 0,
+  EVENT_KIND_FOUND = 1,
+  EVENT_KIND_CONNECTED = 2,
+  EVENT_KIND_DISCONNECTED = 3,
+  EVENT_KIND_UPDATED = 4,
+  EVENT_KIND_MANUFACTURER_DATA = 5,
+  EVENT_KIND_SERVICE_DATA = 6,
+  EVENT_KIND_STATE_UPDATE = 7,
+  EVENT_KIND_COMPLETED = 8,
+  EVENT_KIND_IS_CONNECTED = 9,
+  EVENT_KIND_READ = 10,
+  EVENT_KIND_NOTIFICATION = 11,
+  EVENT_KIND_CONNECTION_STATE = 12,
+  EVENT_KIND_RECONNECT = 13,
+} EventKind;
+
+/**
+ * Result codes returned by the API and passed to completion callbacks
+ */
+typedef enum ErrorCode {
+  SUCCESS = 0,
+  ERROR_FAIL = 1,
+  INVALID_ARGUMENT = 2,
+  ERROR_PERMISSION_DENIED = 101,
+  ERROR_DEVICE_NOT_FOUND = 102,
+  ERROR_NOT_CONNECTED = 103,
+  ERROR_UNEXPECTED_CALLBACK = 104,
+  ERROR_UNEXPECTED_CHARACTERISTIC = 105,
+  ERROR_NO_SUCH_CHARACTERISTIC = 106,
+  ERROR_NOT_SUPPORTED = 107,
+  ERROR_TIMED_OUT = 108,
+  ERROR_UUID = 109,
+  ERROR_INVALID_BD_ADDR = 110,
+  ERROR_RUNTIME_ERROR = 111,
+  ERROR_CANCELLED = 112,
+  ERROR_ALREADY_SCANNING = 113,
+} ErrorCode;
+
+#if defined(BTLEPLUG_C_MOCK)
+/**
+ * Operations whose next invocation can be made to fail with `mock_peripheral_fail_next`, or
+ * with `mock_adapter_fail_next` for the scan operations
+ */
+typedef enum MockOperation {
+#if defined(BTLEPLUG_C_MOCK)
+  CONNECT = 0,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  DISCONNECT = 1,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  DISCOVER_SERVICES = 2,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  READ = 3,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  WRITE = 4,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  SUBSCRIBE = 5,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  UNSUBSCRIBE = 6,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  READ_DESCRIPTOR = 7,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  WRITE_DESCRIPTOR = 8,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  START_SCAN = 9,
+#endif
+#if defined(BTLEPLUG_C_MOCK)
+  STOP_SCAN = 10,
+#endif
+} MockOperation;
+#endif
+
+typedef struct CModule CModule;
+
+/**
+ * Handle to an operation started by one of the asynchronous peripheral functions, released
+ * with `free_operation`
+ */
+typedef struct COperation COperation;
+
+typedef struct CPeripheral CPeripheral;
+
+typedef int (*PeripheralFoundCallback)(uint64_t id, struct CPeripheral *peripheral, const Uuid *services, int service_count, void *user_data);
+
+typedef void (*PeripheralEventCallback)(uint64_t id, void *user_data);
+
+/**
+ * Callbacks for adapter events, any of which may be null
+ *
+ * The signatures are spelled out rather than aliased so the generated header keeps them
+ * nullable function pointers.
+ */
+typedef struct EventCallbacks {
+  int (*found)(uint64_t id, struct CPeripheral *peripheral, const Uuid *services, int service_count, void *user_data);
+  void (*disconnected)(uint64_t id, void *user_data);
+  void (*connected)(uint64_t id, void *user_data);
+  void (*updated)(uint64_t id, void *user_data);
+  void (*manufacturer_data)(uint64_t id, uint16_t manufacturer_id, const uint8_t *data, int data_length, void *user_data);
+  void (*service_data)(uint64_t id, Uuid service_uuid, const uint8_t *data, int data_length, void *user_data);
+  /**
+   * Receives 0 for unknown, 1 for powered on and 2 for powered off
+   */
+  void (*state_update)(int state, void *user_data);
+  /**
+   * Passed back unchanged to every callback
+   */
+  void *user_data;
+} EventCallbacks;
+
+/**
+ * Restricts `module_get_peripherals` to matching peripherals
+ */
+typedef struct PeripheralFilter {
+  /**
+   * 0 matches any peripheral, 1 only connected and 2 only disconnected ones
+   */
+  int connection_state;
+  /**
+   * Unless nil, only peripherals advertising this service match
+   */
+  Uuid service_uuid;
+} PeripheralFilter;
+
+typedef void (*IsConnectedCallback)(int result, int connected, void *user_data);
+
+typedef void (*CompletedCallback)(int result, void *user_data);
+
+typedef struct ManufacturerDataDescriptor {
+  uint16_t manufacturer_id;
+  uint8_t *data;
+  int data_length;
+} ManufacturerDataDescriptor;
+
+typedef struct ServiceDataDescriptor {
+  Uuid uuid;
+  uint8_t *data;
+  int data_length;
+} ServiceDataDescriptor;
+
+typedef struct PeripheralPropertiesDescriptor {
+  uint64_t address;
+  /**
+   * 0 when unknown, 1 for public and 2 for random addresses
+   */
+  int address_type;
+  /**
+   * Null when the peripheral did not advertise a name
+   */
+  char *local_name;
+  bool has_tx_power_level;
+  int16_t tx_power_level;
+  bool has_rssi;
+  int16_t rssi;
+  struct ManufacturerDataDescriptor *manufacturer_data;
+  int manufacturer_data_count;
+  struct ServiceDataDescriptor *service_data;
+  int service_data_count;
+  Uuid *services;
+  int service_count;
+} PeripheralPropertiesDescriptor;
+
+typedef void (*NotifyCallback)(uint64_t id, Uuid service_uuid, Uuid uuid, const uint8_t *data, int data_length, void *user_data);
+
+typedef void (*ReadCallback)(int result, const uint8_t *data, int data_length, void *user_data);
+
+/**
+ * A characteristic recorded as subscribed by `peripheral_get_subscriptions`
+ */
+typedef struct Subscription {
+  Uuid service_uuid;
+  Uuid uuid;
+} Subscription;
+
+/**
+ * The host owns `peripheral` and releases it with `free_peripheral`
+ */
+typedef struct FoundEvent {
+  uint64_t id;
+  struct CPeripheral *peripheral;
+  Uuid *services;
+  int service_count;
+} FoundEvent;
+
+typedef struct PeripheralEvent {
+  uint64_t id;
+} PeripheralEvent;
+
+typedef struct ManufacturerDataEvent {
+  uint64_t id;
+  uint16_t manufacturer_id;
+  uint8_t *data;
+  int data_length;
+} ManufacturerDataEvent;
+
+typedef struct ServiceDataEvent {
+  uint64_t id;
+  Uuid service_uuid;
+  uint8_t *data;
+  int data_length;
+} ServiceDataEvent;
+
+typedef struct StateUpdateEvent {
+  int state;
+} StateUpdateEvent;
+
+typedef struct CompletedEvent {
+  int result;
+} CompletedEvent;
+
+typedef struct IsConnectedEvent {
+  int result;
+  int connected;
+} IsConnectedEvent;
+
+typedef struct ReadEvent {
+  int result;
+  uint8_t *data;
+  int data_length;
+} ReadEvent;
+
+typedef struct NotificationEvent {
+  uint64_t id;
+  Uuid service_uuid;
+  Uuid uuid;
+  uint8_t *data;
+  int data_length;
+} NotificationEvent;
+
+typedef struct ConnectionStateEvent {
+  uint64_t id;
+  enum ConnectionState state;
+  int reason;
+} ConnectionStateEvent;
+
+typedef struct ReconnectEvent {
+  uint64_t id;
+  uint32_t attempt;
+  int result;
+} ReconnectEvent;
+
+typedef union ModuleEventData {
+  struct FoundEvent found;
+  /**
+   * Connected, disconnected and updated events
+   */
+  struct PeripheralEvent peripheral;
+  struct ManufacturerDataEvent manufacturer_data;
+  struct ServiceDataEvent service_data;
+  struct StateUpdateEvent state_update;
+  struct CompletedEvent completed;
+  struct IsConnectedEvent is_connected;
+  struct ReadEvent read;
+  struct NotificationEvent notification;
+  struct ConnectionStateEvent connection_state;
+  struct ReconnectEvent reconnect;
+} ModuleEventData;
+
+/**
+ * An event collected with `module_poll_event`, released with `free_event`
+ */
+typedef struct ModuleEvent {
+  enum EventKind kind;
+  /**
+   * The user_data passed alongside the callback the event replaces
+   */
+  void *user_data;
+  union ModuleEventData data;
+} ModuleEvent;
+
+/**
+ * How `peripheral_set_reconnect_policy` retries a dropped connection
+ */
+typedef struct ReconnectPolicy {
+  /**
+   * Attempts made before giving up
+   */
+  uint32_t max_attempts;
+  /**
+   * Delay before the first attempt, doubled after each failed one
+   */
+  uint32_t initial_delay_ms;
+  /**
+   * Upper bound on the delay between attempts
+   */
+  uint32_t max_delay_ms;
+  /**
+   * Up to this percentage of each delay is added at random, so peripherals dropped together
+   * do not retry in lockstep
+   */
+  uint32_t jitter_percent;
+  /**
+   * Timeout for each attempt, covering the connection, service discovery and resubscription.
+   * 0 waits indefinitely.
+   */
+  uint32_t timeout_ms;
+} ReconnectPolicy;
+
+/**
+ * Options for `start_scan_with_options`. Zeroed fields disable their filter.
+ */
+typedef struct ScanOptions {
+  /**
+   * Passed to the platform as with `start_scan_peripherals`
+   */
+  const Uuid *service_uuids;
+  int service_uuid_count;
+  /**
+   * Unless null, only peripherals whose local name starts with this prefix are reported
+   */
+  const char *name_prefix;
+  /**
+   * When set, only peripherals advertising manufacturer data under `manufacturer_id` are
+   * reported
+   */
+  bool has_manufacturer_id;
+  uint16_t manufacturer_id;
+  /**
+   * Unless 0, peripherals whose signal is weaker than this many dBm are not reported
+   */
+  int min_rssi;
+  /**
+   * Unless 0, a peripheral is reported at most once within this many milliseconds
+   */
+  uint32_t dedupe_window_ms;
+} ScanOptions;
+
+typedef struct ServiceDescriptors {
+  int service_count;
+} ServiceDescriptors;
+
+typedef struct ServiceDescriptor {
+  Uuid uuid;
+  int characteristic_count;
+} ServiceDescriptor;
+
+typedef struct CharacteristicDescriptor {
+  Uuid uuid;
+  CharPropFlags properties;
+  int descriptor_count;
+} CharacteristicDescriptor;
+
+typedef struct CharacteristicDescriptorDescriptor {
+  Uuid uuid;
+} CharacteristicDescriptorDescriptor;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+void set_log_level(int level);
+
+int list_adapters(char ***adapters, int *count);
+
+int free_adapter_list(char **adapters, int count);
+
+int create_module(struct CModule **module);
+
+/**
+ * Binds the module to the adapter whose name, as returned by `list_adapters`, is `adapter_name`,
+ * or to the adapter at `adapter_index` when the name is null
+ */
+int create_module_with_adapter(struct CModule **module, int adapter_index, const char *adapter_name);
+
+int set_event_callbacks(struct CModule *module, PeripheralFoundCallback found, PeripheralEventCallback disconnected, void *user_data);
+
+/**
+ * Sets the module's event callbacks, replacing those from an earlier call. Listeners added with
+ * `module_add_event_listener` are unaffected.
+ */
+int set_event_callbacks_ex(struct CModule *module, const struct EventCallbacks *callbacks);
+
+int start_scan_peripherals(struct CModule *module, Uuid *service_uuids, int32_t service_uuid_count);
+
+/**
+ * Stops the running scan. The scan is still running if the adapter fails to stop it.
+ */
+int stop_scan_peripherals(struct CModule *module);
+
+/**
+ * Describes the adapter the module is bound to, released with `free_string`
+ */
+int module_get_adapter_info(struct CModule *module, char **info);
+
+/**
+ * Reports whether the adapter is powered, using the values passed to the `state_update`
+ * callback: 0 for unknown, 1 for powered on and 2 for powered off
+ */
+int module_get_adapter_state(struct CModule *module, int *state);
+
+/**
+ * Opens a handle for a peripheral the adapter already knows about, from an earlier scan or the
+ * system's cache, without scanning again. Fails with `ERROR_DEVICE_NOT_FOUND` otherwise.
+ */
+int module_get_peripheral_by_address(struct CModule *module, uint64_t address, struct CPeripheral **peripheral);
+
+/**
+ * Like `module_get_peripheral_by_address`, matching the id reported by `peripheral_get_id`
+ */
+int module_get_peripheral_by_id(struct CModule *module, const char *id, struct CPeripheral **peripheral);
+
+/**
+ * Opens a handle for each peripheral the adapter knows about, or only for those matching
+ * `filter` when it is not null. Release the list with `free_peripheral_list`.
+ */
+int module_get_peripherals(struct CModule *module, const struct PeripheralFilter *filter, struct CPeripheral ***peripherals, int *count);
+
+/**
+ * Releases the list and every handle in it
+ */
+int free_peripheral_list(struct CPeripheral **peripherals, int count);
+
+int peripheral_get_id(struct CPeripheral *peripheral, const char **id);
+
+int peripheral_get_address(struct CPeripheral *peripheral, uint64_t *address);
+
+/**
+ * Aborts the operation, whose callback then receives `ERROR_CANCELLED`. Has no effect once the
+ * callback has run.
+ */
+int operation_cancel(struct COperation *operation);
+
+int free_operation(struct COperation *operation);
+
+int peripheral_is_connected(struct CPeripheral *peripheral, uint32_t timeout_ms, IsConnectedCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_connect(struct CPeripheral *peripheral, uint32_t timeout_ms, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_disconnect(struct CPeripheral *peripheral, uint32_t timeout_ms, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_discover_services(struct CPeripheral *peripheral, uint32_t timeout_ms, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_get_advertised_services(struct CPeripheral *peripheral, Uuid **services, int *service_count);
+
+int free_advertised_services(Uuid *services, int service_count);
+
+int peripheral_get_services(struct CPeripheral *peripheral, uint8_t **service_descriptors);
+
+/**
+ * Returns the peripheral's advertisement properties as of its latest event or lookup. Release
+ * them with `free_peripheral_properties`. It does not block, so callbacks can call it.
+ */
+int peripheral_get_properties(struct CPeripheral *peripheral, struct PeripheralPropertiesDescriptor **properties);
+
+int free_peripheral_properties(struct PeripheralPropertiesDescriptor *properties);
+
+int free_peripheral_services(uint8_t **services);
+
+/**
+ * Delivers every notification from the peripheral to `notify_callback`, replacing the listener
+ * from an earlier call on this handle. The listener stops when the handle is released.
+ */
+int peripheral_register_notification_events(struct CPeripheral *peripheral, CompletedCallback ready, NotifyCallback notify_callback, void *user_data);
+
+/**
+ * Stops the listener started by `peripheral_register_notification_events`. Does nothing if
+ * none is running.
+ */
+int peripheral_unregister_notification_events(struct CPeripheral *peripheral);
+
+int peripheral_subscribe(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, uint32_t timeout_ms, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_unsubscribe(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, uint32_t timeout_ms, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_write(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, bool with_response, uint8_t *data, uint32_t data_length, uint32_t timeout_ms, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_read(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, uint32_t timeout_ms, ReadCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_read_descriptor(struct CPeripheral *peripheral, Uuid service_uuid, Uuid characteristic_uuid, Uuid uuid, uint32_t timeout_ms, ReadCallback completed_callback, void *user_data, struct COperation **operation);
+
+int peripheral_write_descriptor(struct CPeripheral *peripheral, Uuid service_uuid, Uuid characteristic_uuid, Uuid uuid, uint8_t *data, uint32_t data_length, uint32_t timeout_ms, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+const char *get_last_module_error(struct CModule *module);
+
+const char *peripheral_get_last_error(struct CPeripheral *peripheral);
+
+int free_module(struct CModule *module);
+
+/**
+ * Releases one reference to the peripheral's handle. Each function returning a peripheral,
+ * including the `found` callback, hands out a reference to the same handle for a device, which
+ * is freed once every reference is released.
+ */
+int free_peripheral(struct CPeripheral *peripheral);
+
+int free_string(char *s);
+
+/**
+ * Reports the peripheral's connection transitions to `callback` until every reference to the
+ * handle is released, replacing any callback set before. A null callback stops the reports.
+ */
+int peripheral_set_connection_callback(struct CPeripheral *peripheral, void (*callback)(uint64_t id, enum ConnectionState state, int reason, void *user_data), void *user_data);
+
+/**
+ * Returns the characteristics currently subscribed to on the device, through any handle. Release
+ * the list with `free_subscriptions`.
+ */
+int peripheral_get_subscriptions(struct CPeripheral *peripheral, struct Subscription **subscriptions, int *subscription_count);
+
+int free_subscriptions(struct Subscription *subscriptions, int subscription_count);
+
+/**
+ * With `enabled` non-zero, a successful `peripheral_connect` discovers services and subscribes
+ * again to every characteristic subscribed before, and a disconnect keeps them recorded for
+ * that. If restoring them fails, the connection is dropped and the connect fails with that
+ * error. Unsubscribing while disconnected removes a characteristic from the set.
+ */
+int peripheral_set_auto_resubscribe(struct CPeripheral *peripheral, int enabled);
+
+/**
+ * Subscribes to the characteristic and routes its notifications to `notify_callback` only,
+ * replacing any handler registered for it through this handle. `completed_callback` receives
+ * the result of the subscription, and both get `user_data`. Notifications stop when the
+ * characteristic is unsubscribed or the handle is released.
+ */
+int peripheral_subscribe_with_callback(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, uint32_t timeout_ms, NotifyCallback notify_callback, CompletedCallback completed_callback, void *user_data, struct COperation **operation);
+
+/**
+ * Adds a listener for the adapter's events alongside any others, returning its id in
+ * `listener_id` for `module_remove_event_listener`
+ */
+int module_add_event_listener(struct CModule *module, const struct EventCallbacks *callbacks, uint64_t *listener_id);
+
+/**
+ * Stops delivering events to the listener. Events already handed to it may still arrive.
+ */
+int module_remove_event_listener(struct CModule *module, uint64_t listener_id);
+
+#if defined(BTLEPLUG_C_MOCK)
+int create_module_mock(struct CModule **module);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+/**
+ * Makes `list_adapters`, `create_module` and `create_module_with_adapter` enumerate mock adapters
+ * with these names instead of the platform's. A null `names` restores the platform adapters.
+ */
+int mock_set_adapters(const char *const *names, int count);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_add_peripheral(struct CModule *module, uint64_t address, const char *local_name, int16_t rssi);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_advertise_services(struct CModule *module, uint64_t address, const Uuid *services, int service_count);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_set_manufacturer_data(struct CModule *module, uint64_t address, uint16_t manufacturer_id, const uint8_t *data, uint32_t data_length);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_set_service_data(struct CModule *module, uint64_t address, Uuid service_uuid, const uint8_t *data, uint32_t data_length);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_set_rssi(struct CModule *module, uint64_t address, int16_t rssi);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_set_tx_power_level(struct CModule *module, uint64_t address, int16_t tx_power_level);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_add_characteristic(struct CModule *module, uint64_t address, Uuid service_uuid, Uuid uuid, uint8_t properties);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_add_descriptor(struct CModule *module, uint64_t address, Uuid service_uuid, Uuid characteristic_uuid, Uuid uuid);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_set_value(struct CModule *module, uint64_t address, Uuid service_uuid, Uuid uuid, const uint8_t *data, uint32_t data_length);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_set_descriptor_value(struct CModule *module, uint64_t address, Uuid service_uuid, Uuid characteristic_uuid, Uuid uuid, const uint8_t *data, uint32_t data_length);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_notify(struct CModule *module, uint64_t address, Uuid service_uuid, Uuid uuid, const uint8_t *data, uint32_t data_length);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_drop_connection(struct CModule *module, uint64_t address);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_fail_next(struct CModule *module, uint64_t address, int operation, int result);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_adapter_fail_next(struct CModule *module, int operation, int result);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_adapter_set_state(struct CModule *module, int state);
+#endif
+
+#if defined(BTLEPLUG_C_MOCK)
+int mock_peripheral_set_latency(struct CModule *module, uint64_t address, uint32_t latency_ms);
+#endif
+
+/**
+ * Switches the module from callbacks to polling. Up to `capacity` events are held, after which
+ * new peripheral and adapter events are dropped. Operation results are always queued, so every
+ * operation still completes.
+ */
+int module_enable_event_queue(struct CModule *module, uint32_t capacity);
+
+/**
+ * Waits up to `timeout_ms` for the next event, indefinitely when negative. `event->kind` is
+ * `EVENT_KIND_NONE` when nothing arrived in time.
+ */
+int module_poll_event(struct CModule *module, struct ModuleEvent *event, int timeout_ms);
+
+/**
+ * File descriptor that polls readable while events are queued, for integrating with `select`
+ * or `epoll`. Returns -1 before the queue is enabled and on platforms without one.
+ */
+int module_event_fd(struct CModule *module);
+
+/**
+ * Releases the buffers held by an event. Peripheral handles from found events are owned by the
+ * host and are not released.
+ */
+int free_event(struct ModuleEvent *event);
+
+/**
+ * Reconnects the peripheral when its connection drops without `peripheral_disconnect` being
+ * called, replacing any policy set through another handle for the same device. The optional
+ * `callback` receives the result of each attempt; a failed last attempt means reconnection gave
+ * up. A null policy turns reconnection off.
+ */
+int peripheral_set_reconnect_policy(struct CPeripheral *peripheral, const struct ReconnectPolicy *policy, void (*callback)(uint64_t id, uint32_t attempt, int result, void *user_data), void *user_data);
+
+/**
+ * Like `start_scan_peripherals`, additionally filtering the peripherals reported to the `found`
+ * callback. A null `options` scans without filters.
+ */
+int start_scan_with_options(struct CModule *module, const struct ScanOptions *options);
+
+/**
+ * Like `start_scan_with_options`, stopping the scan after `duration_ms`. `done_callback`
+ * receives `SUCCESS` then, or `ERROR_CANCELLED` when `stop_scan_peripherals` got there first. If
+ * the adapter fails to stop it, the scan keeps running and `done_callback` receives the error.
+ */
+int start_scan_for(struct CModule *module, const struct ScanOptions *options, uint32_t duration_ms, CompletedCallback done_callback, void *user_data);
+
+/**
+ * Reports whether a scan is running, as 1 or 0
+ */
+int module_is_scanning(struct CModule *module, int *scanning);
+
+int peripheral_is_connected_sync(struct CPeripheral *peripheral, int *connected, uint32_t timeout_ms);
+
+int peripheral_connect_sync(struct CPeripheral *peripheral, uint32_t timeout_ms);
+
+int peripheral_disconnect_sync(struct CPeripheral *peripheral, uint32_t timeout_ms);
+
+int peripheral_discover_services_sync(struct CPeripheral *peripheral, uint32_t timeout_ms);
+
+int peripheral_subscribe_sync(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, uint32_t timeout_ms);
+
+int peripheral_unsubscribe_sync(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, uint32_t timeout_ms);
+
+int peripheral_write_sync(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, bool with_response, const uint8_t *data, uint32_t data_length, uint32_t timeout_ms);
+
+/**
+ * On success `data` receives a buffer to release with `free_data`
+ */
+int peripheral_read_sync(struct CPeripheral *peripheral, Uuid service_uuid, Uuid uuid, uint8_t **data, int *data_length, uint32_t timeout_ms);
+
+/**
+ * On success `data` receives a buffer to release with `free_data`
+ */
+int peripheral_read_descriptor_sync(struct CPeripheral *peripheral, Uuid service_uuid, Uuid characteristic_uuid, Uuid uuid, uint8_t **data, int *data_length, uint32_t timeout_ms);
+
+int peripheral_write_descriptor_sync(struct CPeripheral *peripheral, Uuid service_uuid, Uuid characteristic_uuid, Uuid uuid, const uint8_t *data, uint32_t data_length, uint32_t timeout_ms);
+
+int free_data(uint8_t *data, int data_length);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BTLEPLUG_C_H */
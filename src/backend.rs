@@ -0,0 +1,304 @@
+//! Dispatches between the platform btleplug implementation and the in-memory mock, so the FFI
+//! layer does not need to know which one a module was created with.
+
+#[cfg(any(test, feature = "mock"))]
+use crate::mock::{MockAdapter, MockPeripheral};
+use btleplug::api::{
+    BDAddr, Central as _, CentralState, Characteristic, Descriptor, Manager as _, Peripheral as _,
+    PeripheralProperties, ScanFilter, Service, ValueNotification, WriteType,
+};
+use btleplug::platform;
+use btleplug::{Error, Result as BleResult};
+use futures::{Stream, StreamExt};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::pin::Pin;
+use uuid::Uuid;
+
+macro_rules! dispatch {
+    ($self:ident, $inner:ident => $body:expr) => {
+        match $self {
+            Self::Platform($inner) => $body,
+            #[cfg(any(test, feature = "mock"))]
+            Self::Mock($inner) => $body,
+        }
+    };
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum PeripheralId {
+    Platform(platform::PeripheralId),
+    #[cfg(any(test, feature = "mock"))]
+    Mock(BDAddr),
+}
+
+impl Display for PeripheralId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeripheralId::Platform(id) => id.fmt(f),
+            #[cfg(any(test, feature = "mock"))]
+            PeripheralId::Mock(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// Mirror of [`btleplug::api::CentralEvent`] carrying a backend [`PeripheralId`]
+#[derive(Clone, Debug)]
+pub(crate) enum CentralEvent {
+    DeviceDiscovered(PeripheralId),
+    DeviceUpdated(PeripheralId),
+    DeviceConnected(PeripheralId),
+    DeviceDisconnected(PeripheralId),
+    ManufacturerDataAdvertisement {
+        id: PeripheralId,
+        manufacturer_data: HashMap<u16, Vec<u8>>,
+    },
+    ServiceDataAdvertisement {
+        id: PeripheralId,
+        service_data: HashMap<Uuid, Vec<u8>>,
+    },
+    ServicesAdvertisement {
+        id: PeripheralId,
+        services: Vec<Uuid>,
+    },
+    StateUpdate(CentralState),
+}
+
+impl From<btleplug::api::CentralEvent> for CentralEvent {
+    fn from(event: btleplug::api::CentralEvent) -> Self {
+        use btleplug::api::CentralEvent as E;
+        match event {
+            E::DeviceDiscovered(id) => CentralEvent::DeviceDiscovered(PeripheralId::Platform(id)),
+            E::DeviceUpdated(id) => CentralEvent::DeviceUpdated(PeripheralId::Platform(id)),
+            E::DeviceConnected(id) => CentralEvent::DeviceConnected(PeripheralId::Platform(id)),
+            E::DeviceDisconnected(id) => {
+                CentralEvent::DeviceDisconnected(PeripheralId::Platform(id))
+            }
+            E::ManufacturerDataAdvertisement {
+                id,
+                manufacturer_data,
+            } => CentralEvent::ManufacturerDataAdvertisement {
+                id: PeripheralId::Platform(id),
+                manufacturer_data,
+            },
+            E::ServiceDataAdvertisement { id, service_data } => {
+                CentralEvent::ServiceDataAdvertisement {
+                    id: PeripheralId::Platform(id),
+                    service_data,
+                }
+            }
+            E::ServicesAdvertisement { id, services } => CentralEvent::ServicesAdvertisement {
+                id: PeripheralId::Platform(id),
+                services,
+            },
+            E::StateUpdate(state) => CentralEvent::StateUpdate(state),
+        }
+    }
+}
+
+pub(crate) type EventStream = Pin<Box<dyn Stream<Item = CentralEvent> + Send>>;
+pub(crate) type NotificationStream = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+#[derive(Clone)]
+pub(crate) enum Adapter {
+    Platform(platform::Adapter),
+    #[cfg(any(test, feature = "mock"))]
+    Mock(MockAdapter),
+}
+
+impl Adapter {
+    pub(crate) async fn events(&self) -> BleResult<EventStream> {
+        match self {
+            Adapter::Platform(a) => Ok(Box::pin(a.events().await?.map(CentralEvent::from))),
+            #[cfg(any(test, feature = "mock"))]
+            Adapter::Mock(a) => a.events().await,
+        }
+    }
+
+    pub(crate) async fn start_scan(&self, filter: ScanFilter) -> BleResult<()> {
+        dispatch!(self, a => a.start_scan(filter).await)
+    }
+
+    pub(crate) async fn stop_scan(&self) -> BleResult<()> {
+        dispatch!(self, a => a.stop_scan().await)
+    }
+
+    pub(crate) async fn adapter_info(&self) -> BleResult<String> {
+        dispatch!(self, a => a.adapter_info().await)
+    }
+
+    pub(crate) async fn adapter_state(&self) -> BleResult<CentralState> {
+        dispatch!(self, a => a.adapter_state().await)
+    }
+
+    pub(crate) async fn peripherals(&self) -> BleResult<Vec<Peripheral>> {
+        Ok(match self {
+            Adapter::Platform(a) => a
+                .peripherals()
+                .await?
+                .into_iter()
+                .map(Peripheral::Platform)
+                .collect(),
+            #[cfg(any(test, feature = "mock"))]
+            Adapter::Mock(a) => a
+                .peripherals()
+                .await?
+                .into_iter()
+                .map(Peripheral::Mock)
+                .collect(),
+        })
+    }
+
+    pub(crate) async fn peripheral(&self, id: &PeripheralId) -> BleResult<Peripheral> {
+        match (self, id) {
+            (Adapter::Platform(a), PeripheralId::Platform(id)) => {
+                Ok(Peripheral::Platform(a.peripheral(id).await?))
+            }
+            #[cfg(any(test, feature = "mock"))]
+            (Adapter::Mock(a), PeripheralId::Mock(addr)) => {
+                Ok(Peripheral::Mock(a.peripheral(addr).await?))
+            }
+            #[cfg(any(test, feature = "mock"))]
+            _ => Err(Error::DeviceNotFound),
+        }
+    }
+
+    /// Looks up a peripheral by the id its [`PeripheralId`] displays as
+    pub(crate) async fn peripheral_by_id(&self, id: &str) -> BleResult<Peripheral> {
+        match self {
+            Adapter::Platform(a) => match platform_id(id) {
+                Some(id) => Ok(Peripheral::Platform(a.peripheral(&id).await?)),
+                // BlueZ ids cannot be built from their string, so the known ones are compared
+                None => a
+                    .peripherals()
+                    .await?
+                    .into_iter()
+                    .find(|p| p.id().to_string() == id)
+                    .map(Peripheral::Platform)
+                    .ok_or(Error::DeviceNotFound),
+            },
+            #[cfg(any(test, feature = "mock"))]
+            Adapter::Mock(a) => {
+                let address = id.parse().map_err(|_| Error::DeviceNotFound)?;
+                Ok(Peripheral::Mock(a.peripheral(&address).await?))
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn platform_id(id: &str) -> Option<platform::PeripheralId> {
+    id.parse::<BDAddr>().ok().map(platform::PeripheralId::from)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn platform_id(id: &str) -> Option<platform::PeripheralId> {
+    Uuid::parse_str(id).ok().map(platform::PeripheralId::from)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+fn platform_id(_id: &str) -> Option<platform::PeripheralId> {
+    None
+}
+
+/// The adapters modules can be created for, which are the mock ones while `mock_set_adapters` has
+/// installed them
+pub(crate) async fn adapters() -> BleResult<Vec<Adapter>> {
+    #[cfg(any(test, feature = "mock"))]
+    if let Some(adapters) = crate::mock::installed_adapters() {
+        return Ok(adapters.into_iter().map(Adapter::Mock).collect());
+    }
+    let manager = platform::Manager::new().await?;
+    Ok(manager
+        .adapters()
+        .await?
+        .into_iter()
+        .map(Adapter::Platform)
+        .collect())
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Peripheral {
+    Platform(platform::Peripheral),
+    #[cfg(any(test, feature = "mock"))]
+    Mock(MockPeripheral),
+}
+
+impl Peripheral {
+    pub(crate) fn id(&self) -> PeripheralId {
+        match self {
+            Peripheral::Platform(p) => PeripheralId::Platform(p.id()),
+            #[cfg(any(test, feature = "mock"))]
+            Peripheral::Mock(p) => PeripheralId::Mock(p.address()),
+        }
+    }
+
+    pub(crate) fn address(&self) -> BDAddr {
+        dispatch!(self, p => p.address())
+    }
+
+    pub(crate) async fn properties(&self) -> BleResult<Option<PeripheralProperties>> {
+        dispatch!(self, p => p.properties().await)
+    }
+
+    pub(crate) fn services(&self) -> BTreeSet<Service> {
+        dispatch!(self, p => p.services())
+    }
+
+    pub(crate) fn characteristics(&self) -> BTreeSet<Characteristic> {
+        dispatch!(self, p => p.characteristics())
+    }
+
+    pub(crate) async fn is_connected(&self) -> BleResult<bool> {
+        dispatch!(self, p => p.is_connected().await)
+    }
+
+    pub(crate) async fn connect(&self) -> BleResult<()> {
+        dispatch!(self, p => p.connect().await)
+    }
+
+    pub(crate) async fn disconnect(&self) -> BleResult<()> {
+        dispatch!(self, p => p.disconnect().await)
+    }
+
+    pub(crate) async fn discover_services(&self) -> BleResult<()> {
+        dispatch!(self, p => p.discover_services().await)
+    }
+
+    pub(crate) async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> BleResult<()> {
+        dispatch!(self, p => p.write(characteristic, data, write_type).await)
+    }
+
+    pub(crate) async fn read(&self, characteristic: &Characteristic) -> BleResult<Vec<u8>> {
+        dispatch!(self, p => p.read(characteristic).await)
+    }
+
+    pub(crate) async fn subscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        dispatch!(self, p => p.subscribe(characteristic).await)
+    }
+
+    pub(crate) async fn unsubscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        dispatch!(self, p => p.unsubscribe(characteristic).await)
+    }
+
+    pub(crate) async fn notifications(&self) -> BleResult<NotificationStream> {
+        dispatch!(self, p => p.notifications().await)
+    }
+
+    pub(crate) async fn write_descriptor(
+        &self,
+        descriptor: &Descriptor,
+        data: &[u8],
+    ) -> BleResult<()> {
+        dispatch!(self, p => p.write_descriptor(descriptor, data).await)
+    }
+
+    pub(crate) async fn read_descriptor(&self, descriptor: &Descriptor) -> BleResult<Vec<u8>> {
+        dispatch!(self, p => p.read_descriptor(descriptor).await)
+    }
+}
@@ -0,0 +1,575 @@
+//! Per-peripheral connection state, driven by the connect and disconnect operations and by the
+//! adapter's connection events, along with the subscriptions to restore on connection. The state
+//! outlives the device's handle, so it is kept per module and keyed by peripheral id, while the
+//! callback goes with the handle it was set through.
+
+use crate::backend::{Peripheral, PeripheralId};
+use crate::queue::{Event, EventQueue};
+use crate::reconnect::{reconnect, Reconnect};
+use crate::{
+    free_raw_slice, get_long_addr, into_raw_slice, CPeripheral, PeripheralHandle, UserData,
+    INVALID_ARGUMENT, SUCCESS,
+};
+use log::{debug, error, trace};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{c_int, c_void};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Connection states reported to a peripheral's connection callback
+/// cbindgen:prefix-with-name
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected = 0,
+    Connecting = 1,
+    Connected = 2,
+    Disconnecting = 3,
+}
+
+/// Receives the peripheral's new state, with the result code of the operation which failed to
+/// complete the previous transition as `reason`, or `SUCCESS`
+pub(crate) type ConnectionCallback =
+    extern "C" fn(id: u64, state: ConnectionState, reason: c_int, user_data: *mut c_void);
+
+/// A connection callback, kept until the handle it was set through is released
+#[derive(Clone)]
+struct Callback {
+    callback: ConnectionCallback,
+    user_data: UserData,
+    handle: Weak<PeripheralHandle>,
+}
+
+#[derive(Default)]
+struct Entry {
+    state: ConnectionState,
+    callback: Option<Callback>,
+    /// Characteristics subscribed to, as service and characteristic, restored after reconnecting
+    subscriptions: BTreeSet<(Uuid, Uuid)>,
+    /// Whether `connect` restores the subscriptions, which then also survive a disconnect
+    auto_resubscribe: bool,
+    reconnect: Option<Reconnect>,
+}
+
+/// A characteristic recorded as subscribed by `peripheral_get_subscriptions`
+#[repr(C)]
+pub struct Subscription {
+    service_uuid: Uuid,
+    uuid: Uuid,
+}
+
+#[derive(Default)]
+pub(crate) struct Connections {
+    entries: Mutex<HashMap<PeripheralId, Entry>>,
+}
+
+impl Connections {
+    /// Brings a settled state in line with the peripheral after an adapter connection event,
+    /// which can arrive after an operation already moved on. Connecting and disconnecting belong
+    /// to the operation in progress, which settles them itself.
+    pub(crate) async fn refresh(&self, events: &EventQueue, peripheral: &Peripheral, address: u64) {
+        let Ok(connected) = peripheral.is_connected().await else {
+            return;
+        };
+        let (from, state) = if connected {
+            (ConnectionState::Disconnected, ConnectionState::Connected)
+        } else {
+            (ConnectionState::Connected, ConnectionState::Disconnected)
+        };
+        let id = peripheral.id();
+        if self.transition(events, &id, address, Some(from), state, SUCCESS) && !connected {
+            self.start_reconnect(&id);
+        }
+    }
+
+    /// Moves the peripheral to `state`, reporting it if that is a change. With `from`, only a
+    /// peripheral currently in that state moves. Returns whether the state changed.
+    fn transition(
+        &self,
+        events: &EventQueue,
+        id: &PeripheralId,
+        address: u64,
+        from: Option<ConnectionState>,
+        state: ConnectionState,
+        reason: c_int,
+    ) -> bool {
+        let callback = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.entry(id.clone()).or_default();
+            if entry.state == state || from.is_some_and(|from| from != entry.state) {
+                return false;
+            }
+            debug!("{id} {:?} -> {state:?}", entry.state);
+            entry.state = state;
+            if entry
+                .callback
+                .as_ref()
+                .is_some_and(|c| c.handle.strong_count() == 0)
+            {
+                debug!("Handle for {id} released, dropping its connection callback");
+                entry.callback = None;
+            }
+            entry.callback.clone()
+        };
+
+        if let Some(Callback {
+            callback,
+            user_data,
+            ..
+        }) = callback
+        {
+            let event = Event::ConnectionState {
+                callback,
+                id: address,
+                state,
+                reason,
+            };
+            events.deliver(user_data, event);
+        }
+        true
+    }
+
+    fn set_callback(&self, id: &PeripheralId, callback: Option<Callback>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(id.clone()).or_default().callback = callback;
+    }
+
+    pub(crate) fn set_subscribed(&self, id: &PeripheralId, characteristic: (Uuid, Uuid), on: bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let subscriptions = &mut entries.entry(id.clone()).or_default().subscriptions;
+        if on {
+            subscriptions.insert(characteristic);
+        } else {
+            subscriptions.remove(&characteristic);
+        }
+    }
+
+    /// Forgets the subscriptions once the host disconnects on purpose, unless they are to be
+    /// restored on the next connection
+    pub(crate) fn clear_subscriptions(&self, id: &PeripheralId) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(id) {
+            entry.subscriptions.clear();
+        }
+    }
+
+    pub(crate) fn subscriptions(&self, id: &PeripheralId) -> Vec<(Uuid, Uuid)> {
+        match self.entries.lock().unwrap().get(id) {
+            Some(entry) => entry.subscriptions.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub(crate) fn auto_resubscribe(&self, id: &PeripheralId) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.get(id).is_some_and(|e| e.auto_resubscribe)
+    }
+
+    fn set_auto_resubscribe(&self, id: &PeripheralId, on: bool) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(id.clone()).or_default().auto_resubscribe = on;
+    }
+
+    /// Replaces the reconnect policy, abandoning any reconnection in progress
+    pub(crate) fn set_reconnect(&self, id: &PeripheralId, reconnect: Option<Reconnect>) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(id.clone()).or_default();
+        if let Some(cancel) = entry.reconnect.as_mut().and_then(|r| r.cancel.take()) {
+            cancel.notify_one();
+        }
+        entry.reconnect = reconnect;
+    }
+
+    /// Abandons any reconnection in progress, keeping the policy for later drops
+    pub(crate) fn cancel_reconnect(&self, id: &PeripheralId) {
+        let mut entries = self.entries.lock().unwrap();
+        let reconnect = entries.get_mut(id).and_then(|e| e.reconnect.as_mut());
+        if let Some(cancel) = reconnect.and_then(|r| r.cancel.take()) {
+            debug!("Cancelling reconnection to {id}");
+            cancel.notify_one();
+        }
+    }
+
+    fn start_reconnect(&self, id: &PeripheralId) {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(r) = entries.get_mut(id).and_then(|e| e.reconnect.as_mut()) else {
+            return;
+        };
+        if r.cancel.is_some() {
+            return;
+        }
+        let Some(handle) = r.handle.upgrade() else {
+            debug!("Handle for {id} released, not reconnecting");
+            return;
+        };
+
+        let cancel = Arc::new(Notify::new());
+        r.cancel = Some(Arc::clone(&cancel));
+        tokio::spawn(reconnect(handle, r.policy, r.callback, r.user_data, cancel));
+    }
+
+    pub(crate) fn reconnect_finished(&self, id: &PeripheralId, cancel: &Arc<Notify>) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(r) = entries.get_mut(id).and_then(|e| e.reconnect.as_mut()) {
+            if r.cancel.as_ref().is_some_and(|c| Arc::ptr_eq(c, cancel)) {
+                r.cancel = None;
+            }
+        }
+    }
+}
+
+impl PeripheralHandle {
+    /// Reports a connection transition caused by an operation on this handle
+    pub(crate) fn connection_update(
+        &self,
+        from: Option<ConnectionState>,
+        state: ConnectionState,
+        reason: c_int,
+    ) {
+        let id = self.peripheral.id();
+        let address = get_long_addr(self.peripheral.address());
+        self.connections
+            .transition(&self.events, &id, address, from, state, reason);
+    }
+
+    pub(crate) fn set_subscribed(&self, service_uuid: Uuid, uuid: Uuid, on: bool) {
+        let id = self.peripheral.id();
+        self.connections
+            .set_subscribed(&id, (service_uuid, uuid), on);
+    }
+}
+
+/// Reports the peripheral's connection transitions to `callback` until every reference to the
+/// handle is released, replacing any callback set before. A null callback stops the reports.
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_set_connection_callback(
+    peripheral: *mut CPeripheral,
+    callback: Option<
+        extern "C" fn(id: u64, state: ConnectionState, reason: c_int, user_data: *mut c_void),
+    >,
+    user_data: *mut c_void,
+) -> c_int {
+    trace!("Enter: peripheral_set_connection_callback");
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let ap = &(*peripheral).p;
+    let callback = callback.map(|callback| Callback {
+        callback,
+        user_data: UserData(user_data),
+        handle: Arc::downgrade(ap),
+    });
+    ap.connections.set_callback(&ap.peripheral.id(), callback);
+    SUCCESS
+}
+
+/// Returns the characteristics currently subscribed to on the device, through any handle. Release
+/// the list with `free_subscriptions`.
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_get_subscriptions(
+    peripheral: *mut CPeripheral,
+    subscriptions: *mut *mut Subscription,
+    subscription_count: *mut c_int,
+) -> c_int {
+    trace!("Enter: peripheral_get_subscriptions");
+    if peripheral.is_null() || subscriptions.is_null() || subscription_count.is_null() {
+        return INVALID_ARGUMENT;
+    }
+
+    let ap = &(*peripheral).p;
+    let list = ap
+        .connections
+        .subscriptions(&ap.peripheral.id())
+        .into_iter()
+        .map(|(service_uuid, uuid)| Subscription { service_uuid, uuid })
+        .collect();
+    (*subscriptions, *subscription_count) = into_raw_slice(list);
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free_subscriptions(
+    subscriptions: *mut Subscription,
+    subscription_count: c_int,
+) -> c_int {
+    if subscriptions.is_null() {
+        return SUCCESS;
+    }
+
+    free_raw_slice(subscriptions, subscription_count);
+    SUCCESS
+}
+
+/// With `enabled` non-zero, a successful `peripheral_connect` discovers services and subscribes
+/// again to every characteristic subscribed before, and a disconnect keeps them recorded for
+/// that. If restoring them fails, the connection is dropped and the connect fails with that
+/// error. Unsubscribing while disconnected removes a characteristic from the set.
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_set_auto_resubscribe(
+    peripheral: *mut CPeripheral,
+    enabled: c_int,
+) -> c_int {
+    trace!("Enter: peripheral_set_auto_resubscribe");
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let ap = &(*peripheral).p;
+    ap.connections
+        .set_auto_resubscribe(&ap.peripheral.id(), enabled != 0);
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::tests::{leak, listen, wait, Fixture, ADDRESS, CHARACTERISTIC, SERVICE};
+    use crate::mock::{
+        create_module_mock, mock_add_peripheral, mock_peripheral_drop_connection,
+        mock_peripheral_fail_next, mock_peripheral_notify, MockOperation,
+    };
+    use crate::sync::{
+        peripheral_connect_sync, peripheral_disconnect_sync, peripheral_is_connected_sync,
+        peripheral_subscribe_sync, peripheral_unsubscribe_sync,
+    };
+    use crate::{
+        free_module, free_peripheral, module_get_peripheral_by_address, ERROR_NOT_SUPPORTED,
+        ERROR_TIMED_OUT,
+    };
+    use std::ptr::null_mut;
+    use std::slice::from_raw_parts;
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+
+    type Transition = (u64, ConnectionState, c_int);
+
+    extern "C" fn on_connection(
+        id: u64,
+        state: ConnectionState,
+        reason: c_int,
+        user_data: *mut c_void,
+    ) {
+        let transitions = unsafe { &*(user_data as *const Sender<Transition>) };
+        let _ = transitions.send((id, state, reason));
+    }
+
+    #[test]
+    fn connection_transitions_are_reported() {
+        unsafe {
+            let f = Fixture::new();
+            let (tx, rx) = channel();
+            let transitions = leak(tx) as *const Sender<Transition>;
+            assert_eq!(
+                SUCCESS,
+                peripheral_set_connection_callback(
+                    f.peripheral,
+                    Some(on_connection),
+                    transitions as *mut c_void
+                )
+            );
+
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_fail_next(
+                    f.module,
+                    ADDRESS,
+                    MockOperation::Connect as c_int,
+                    ERROR_TIMED_OUT
+                )
+            );
+            assert_eq!(ERROR_TIMED_OUT, peripheral_connect_sync(f.peripheral, 0));
+            assert_eq!((ADDRESS, ConnectionState::Connecting, SUCCESS), wait(&rx));
+            assert_eq!(
+                (ADDRESS, ConnectionState::Disconnected, ERROR_TIMED_OUT),
+                wait(&rx)
+            );
+
+            f.connect();
+            assert_eq!((ADDRESS, ConnectionState::Connecting, SUCCESS), wait(&rx));
+            assert_eq!((ADDRESS, ConnectionState::Connected, SUCCESS), wait(&rx));
+            assert_eq!(SUCCESS, mock_peripheral_drop_connection(f.module, ADDRESS));
+            assert_eq!((ADDRESS, ConnectionState::Disconnected, SUCCESS), wait(&rx));
+
+            assert_eq!(SUCCESS, peripheral_connect_sync(f.peripheral, 0));
+            assert_eq!(SUCCESS, peripheral_disconnect_sync(f.peripheral, 0));
+            let expected = [
+                ConnectionState::Connecting,
+                ConnectionState::Connected,
+                ConnectionState::Disconnecting,
+                ConnectionState::Disconnected,
+            ];
+            for state in expected {
+                assert_eq!((ADDRESS, state, SUCCESS), wait(&rx));
+            }
+            // The adapter's own connection events repeat states already reported
+            assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        }
+    }
+
+    unsafe fn subscriptions(peripheral: *mut CPeripheral) -> Vec<(Uuid, Uuid)> {
+        let mut list = null_mut();
+        let mut count = 0;
+        assert_eq!(
+            SUCCESS,
+            peripheral_get_subscriptions(peripheral, &mut list, &mut count)
+        );
+        let result = from_raw_parts(list, count as usize)
+            .iter()
+            .map(|s| (s.service_uuid, s.uuid))
+            .collect();
+        free_subscriptions(list, count);
+        result
+    }
+
+    #[test]
+    fn subscriptions_are_restored_on_connect() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert!(subscriptions(f.peripheral).is_empty());
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            assert_eq!(vec![(SERVICE, CHARACTERISTIC)], subscriptions(f.peripheral));
+            let values = listen(f.peripheral);
+
+            // Without automatic resubscription a disconnect forgets the subscriptions
+            assert_eq!(SUCCESS, peripheral_disconnect_sync(f.peripheral, 0));
+            assert!(subscriptions(f.peripheral).is_empty());
+
+            assert_eq!(SUCCESS, peripheral_connect_sync(f.peripheral, 0));
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            assert_eq!(SUCCESS, peripheral_set_auto_resubscribe(f.peripheral, 1));
+            assert_eq!(SUCCESS, peripheral_disconnect_sync(f.peripheral, 0));
+            assert_eq!(vec![(SERVICE, CHARACTERISTIC)], subscriptions(f.peripheral));
+            assert_eq!(SUCCESS, peripheral_connect_sync(f.peripheral, 0));
+            let data = [7u8];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_notify(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    data.as_ptr(),
+                    1
+                )
+            );
+            assert_eq!(vec![7], wait(&values).3);
+
+            // Unsubscribing while disconnected only drops the recorded subscription
+            assert_eq!(SUCCESS, peripheral_disconnect_sync(f.peripheral, 0));
+            assert_eq!(
+                SUCCESS,
+                peripheral_unsubscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            assert!(subscriptions(f.peripheral).is_empty());
+        }
+    }
+
+    #[test]
+    fn connections_fail_when_resubscribing_fails() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            assert_eq!(SUCCESS, peripheral_set_auto_resubscribe(f.peripheral, 1));
+            assert_eq!(SUCCESS, peripheral_disconnect_sync(f.peripheral, 0));
+            let (tx, rx) = channel();
+            let transitions = leak(tx) as *const Sender<Transition>;
+            assert_eq!(
+                SUCCESS,
+                peripheral_set_connection_callback(
+                    f.peripheral,
+                    Some(on_connection),
+                    transitions as *mut c_void
+                )
+            );
+
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_fail_next(
+                    f.module,
+                    ADDRESS,
+                    MockOperation::Subscribe as c_int,
+                    ERROR_NOT_SUPPORTED
+                )
+            );
+            assert_eq!(
+                ERROR_NOT_SUPPORTED,
+                peripheral_connect_sync(f.peripheral, 0)
+            );
+            assert_eq!((ADDRESS, ConnectionState::Connecting, SUCCESS), wait(&rx));
+            assert_eq!(
+                (ADDRESS, ConnectionState::Disconnected, ERROR_NOT_SUPPORTED),
+                wait(&rx)
+            );
+            let mut connected = -1;
+            assert_eq!(
+                SUCCESS,
+                peripheral_is_connected_sync(f.peripheral, &mut connected, 0)
+            );
+            assert_eq!(0, connected);
+            assert_eq!(vec![(SERVICE, CHARACTERISTIC)], subscriptions(f.peripheral));
+
+            assert_eq!(SUCCESS, peripheral_connect_sync(f.peripheral, 0));
+            assert_eq!((ADDRESS, ConnectionState::Connecting, SUCCESS), wait(&rx));
+            assert_eq!((ADDRESS, ConnectionState::Connected, SUCCESS), wait(&rx));
+        }
+    }
+
+    #[test]
+    fn dropped_connections_are_reported_without_event_listeners() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            let mut peripheral = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            let (tx, rx) = channel();
+            let transitions = leak(tx) as *const Sender<Transition>;
+            assert_eq!(
+                SUCCESS,
+                peripheral_set_connection_callback(
+                    peripheral,
+                    Some(on_connection),
+                    transitions as *mut c_void
+                )
+            );
+
+            assert_eq!(SUCCESS, peripheral_connect_sync(peripheral, 0));
+            assert_eq!((ADDRESS, ConnectionState::Connecting, SUCCESS), wait(&rx));
+            assert_eq!((ADDRESS, ConnectionState::Connected, SUCCESS), wait(&rx));
+            assert_eq!(SUCCESS, mock_peripheral_drop_connection(module, ADDRESS));
+            assert_eq!((ADDRESS, ConnectionState::Disconnected, SUCCESS), wait(&rx));
+
+            // The callback goes with the last reference to the handle
+            free_peripheral(peripheral);
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            assert_eq!(SUCCESS, peripheral_connect_sync(peripheral, 0));
+            assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+            free_peripheral(peripheral);
+            free_module(module);
+        }
+    }
+}
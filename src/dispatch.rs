@@ -0,0 +1,312 @@
+//! Per-characteristic notification handlers for `peripheral_subscribe_with_callback`. While any
+//! handler is registered, a single dispatcher task per handle reads the peripheral's
+//! notifications and hands each one to the handler registered for its characteristic.
+
+use crate::backend::{NotificationStream, Peripheral};
+use crate::queue::{Event, EventQueue};
+use crate::{
+    clear_operation, get_long_addr, record_error, result_code, set_peripheral_error_str,
+    start_operation, subscribe, COperation, CPeripheral, CompletedCallback, Deadline,
+    NotifyCallback, PeripheralHandle, ServiceIndex, UserData, INVALID_ARGUMENT, SUCCESS,
+};
+use futures::StreamExt;
+use log::{debug, error, info, trace};
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// Handlers keyed by service and characteristic
+type Handlers = Arc<Mutex<HashMap<(Uuid, Uuid), (NotifyCallback, UserData)>>>;
+
+pub(crate) struct Dispatcher {
+    handlers: Handlers,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Notifications only carry the characteristic, so when its service is unknown the handler for
+/// the characteristic takes it, unless handlers for it are registered in several services
+fn find_handler(
+    handlers: &Handlers,
+    service_uuid: Uuid,
+    uuid: Uuid,
+) -> Option<(NotifyCallback, UserData)> {
+    let handlers = handlers.lock().unwrap();
+    if let Some(handler) = handlers.get(&(service_uuid, uuid)) {
+        return Some(*handler);
+    }
+    if !service_uuid.is_nil() {
+        return None;
+    }
+    let mut matching = handlers.iter().filter(|((_, u), _)| *u == uuid);
+    match (matching.next(), matching.next()) {
+        (Some((_, handler)), None) => Some(*handler),
+        _ => None,
+    }
+}
+
+async fn dispatch(
+    peripheral: Peripheral,
+    mut notifications: NotificationStream,
+    events: Arc<EventQueue>,
+    services: ServiceIndex,
+    handlers: Handlers,
+) {
+    let id = get_long_addr(peripheral.address());
+    while let Some(data) = notifications.next().await {
+        let service_uuid = services.service_uuid(&data.uuid);
+        let Some((callback, user_data)) = find_handler(&handlers, service_uuid, data.uuid) else {
+            debug!("No handler for {}, dropping notification", data.uuid);
+            continue;
+        };
+        let event = Event::Notification {
+            callback,
+            id,
+            service_uuid,
+            uuid: data.uuid,
+            data: data.value,
+        };
+        events.deliver(user_data, event);
+    }
+}
+
+impl PeripheralHandle {
+    /// Registers the handler for the characteristic, starting the dispatcher if this is the
+    /// first one
+    pub(crate) async fn add_handler(
+        &self,
+        service_uuid: Uuid,
+        uuid: Uuid,
+        callback: NotifyCallback,
+        user_data: UserData,
+    ) -> Result<(), c_int> {
+        let mut dispatcher = self.dispatcher.lock().await;
+        if dispatcher.is_none() {
+            // Listening before the subscription is made, so no early notification is missed
+            let notifications = match self.peripheral.notifications().await {
+                Ok(notifications) => notifications,
+                Err(e) => return Err(record_error(self, "notifications", &e)),
+            };
+            let handlers = Handlers::default();
+            let task = tokio::spawn(dispatch(
+                self.peripheral.clone(),
+                notifications,
+                Arc::clone(&self.events),
+                self.characteristic_services.clone(),
+                Arc::clone(&handlers),
+            ));
+            *dispatcher = Some(Dispatcher { handlers, task });
+        }
+        let handlers = &dispatcher.as_ref().unwrap().handlers;
+        let handler = (callback, user_data);
+        handlers
+            .lock()
+            .unwrap()
+            .insert((service_uuid, uuid), handler);
+        Ok(())
+    }
+
+    /// Unregisters the handler for the characteristic, stopping the dispatcher with the last one
+    pub(crate) async fn remove_handler(&self, service_uuid: Uuid, uuid: Uuid) {
+        let mut dispatcher = self.dispatcher.lock().await;
+        let Some(d) = dispatcher.as_ref() else {
+            return;
+        };
+        let mut handlers = d.handlers.lock().unwrap();
+        handlers.remove(&(service_uuid, uuid));
+        let unused = handlers.is_empty();
+        drop(handlers);
+        if unused {
+            debug!("Last notification handler removed, stopping the dispatcher");
+            *dispatcher = None;
+        }
+    }
+}
+
+/// Subscribes to the characteristic and routes its notifications to `notify_callback` only,
+/// replacing any handler registered for it through this handle. `completed_callback` receives
+/// the result of the subscription, and both get `user_data`. Notifications stop when the
+/// characteristic is unsubscribed or the handle is released.
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_subscribe_with_callback(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    timeout_ms: u32,
+    notify_callback: NotifyCallback,
+    completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
+) -> c_int {
+    trace!("Enter: peripheral_subscribe_with_callback");
+    clear_operation(operation);
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*peripheral).module;
+
+    if m.runtime.is_none() {
+        error!("null runtime handle");
+        set_peripheral_error_str(&peripheral, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    info!("Subscribing notification handler for {service_uuid}:{uuid}");
+    let runtime = m.runtime.as_ref().unwrap();
+    let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
+    runtime.spawn(async move {
+        let mut result = ap
+            .add_handler(service_uuid, uuid, notify_callback, user_data)
+            .await;
+        if result.is_ok() {
+            result = subscribe(
+                &ap,
+                service_uuid,
+                uuid,
+                Deadline::after(timeout_ms),
+                Some(&cancel),
+            )
+            .await;
+            if result.is_err() {
+                ap.remove_handler(service_uuid, uuid).await;
+            }
+        }
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
+    });
+    trace!("Success: peripheral_subscribe_with_callback");
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::tests::{leak, wait, Fixture, ADDRESS, CHARACTERISTIC, SERVICE};
+    use crate::mock::{mock_peripheral_add_characteristic, mock_peripheral_notify};
+    use crate::sync::{peripheral_discover_services_sync, peripheral_unsubscribe_sync};
+    use btleplug::api::CharPropFlags;
+    use std::ptr::null_mut;
+    use std::slice::from_raw_parts;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::Duration;
+
+    const OTHER: Uuid = Uuid::from_u128(0x1002);
+
+    struct Handler {
+        completed: Sender<c_int>,
+        values: Sender<(Uuid, Vec<u8>)>,
+    }
+
+    extern "C" fn on_completed(result: c_int, user_data: *mut c_void) {
+        let handler = unsafe { &*(user_data as *const Handler) };
+        let _ = handler.completed.send(result);
+    }
+
+    extern "C" fn on_notify(
+        _id: u64,
+        _service_uuid: Uuid,
+        uuid: Uuid,
+        data: *const u8,
+        len: c_int,
+        user_data: *mut c_void,
+    ) {
+        let handler = unsafe { &*(user_data as *const Handler) };
+        let value = unsafe { from_raw_parts(data, len as usize) }.to_vec();
+        let _ = handler.values.send((uuid, value));
+    }
+
+    /// Subscribes with a handler of its own
+    unsafe fn subscribe_with_handler(
+        peripheral: *mut CPeripheral,
+        uuid: Uuid,
+    ) -> Receiver<(Uuid, Vec<u8>)> {
+        let (completed_tx, completed_rx) = channel();
+        let (values_tx, values_rx) = channel();
+        let handler = leak(Handler {
+            completed: completed_tx,
+            values: values_tx,
+        }) as *const Handler;
+        assert_eq!(
+            SUCCESS,
+            peripheral_subscribe_with_callback(
+                peripheral,
+                SERVICE,
+                uuid,
+                0,
+                on_notify,
+                on_completed,
+                handler as *mut c_void,
+                null_mut()
+            )
+        );
+        assert_eq!(SUCCESS, wait(&completed_rx));
+        values_rx
+    }
+
+    unsafe fn notify(f: &Fixture, uuid: Uuid, value: u8) {
+        let data = [value];
+        assert_eq!(
+            SUCCESS,
+            mock_peripheral_notify(f.module, ADDRESS, SERVICE, uuid, data.as_ptr(), 1)
+        );
+    }
+
+    #[test]
+    fn notifications_reach_their_characteristic_handler() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_add_characteristic(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    OTHER,
+                    CharPropFlags::NOTIFY.bits()
+                )
+            );
+            f.connect();
+            assert_eq!(SUCCESS, peripheral_discover_services_sync(f.peripheral, 0));
+            let first = subscribe_with_handler(f.peripheral, CHARACTERISTIC);
+            let second = subscribe_with_handler(f.peripheral, OTHER);
+
+            notify(&f, CHARACTERISTIC, 1);
+            notify(&f, OTHER, 2);
+            assert_eq!((CHARACTERISTIC, vec![1]), wait(&first));
+            assert_eq!((OTHER, vec![2]), wait(&second));
+
+            assert_eq!(
+                SUCCESS,
+                peripheral_unsubscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            notify(&f, CHARACTERISTIC, 3);
+            notify(&f, OTHER, 4);
+            assert_eq!((OTHER, vec![4]), wait(&second));
+            assert!(first.recv_timeout(Duration::from_millis(50)).is_err());
+
+            // The dispatcher stops with the last handler and starts again with the next one
+            assert_eq!(
+                SUCCESS,
+                peripheral_unsubscribe_sync(f.peripheral, SERVICE, OTHER, 0)
+            );
+            let p = &(*f.peripheral).p;
+            assert!(p.dispatcher.try_lock().unwrap().is_none());
+            let first = subscribe_with_handler(f.peripheral, CHARACTERISTIC);
+            notify(&f, CHARACTERISTIC, 5);
+            assert_eq!((CHARACTERISTIC, vec![5]), wait(&first));
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! The module's peripheral handles. Every function handing out a `CPeripheral` returns the one
+//! handle kept for that device, counting a reference the host releases with `free_peripheral`,
+//! so the per-peripheral state behind it is shared and a device keeps the same handle while any
+//! reference is held.
+
+use crate::backend::{Peripheral, PeripheralId};
+use crate::{CPeripheral, ModuleInt};
+use btleplug::api::PeripheralProperties;
+use std::collections::HashMap;
+use std::mem::forget;
+use std::sync::Arc;
+use uuid::Uuid;
+
+struct Entry {
+    handle: *mut CPeripheral,
+    refs: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct Handles {
+    entries: HashMap<PeripheralId, Entry>,
+}
+
+impl Handles {
+    pub(crate) fn contains(&self, id: &PeripheralId) -> bool {
+        self.entries.contains_key(id)
+    }
+}
+
+// The handles are only dereferenced while a reference to them is held
+unsafe impl Send for Handles {}
+unsafe impl Sync for Handles {}
+
+/// One reference to a cached handle, released when dropped unless handed to the host
+pub(crate) struct PeripheralRef(*mut CPeripheral);
+
+unsafe impl Send for PeripheralRef {}
+
+impl PeripheralRef {
+    /// Hands the reference to the host, which releases it with `free_peripheral`
+    pub(crate) fn into_raw(self) -> *mut CPeripheral {
+        let handle = self.0;
+        forget(self);
+        handle
+    }
+}
+
+impl Drop for PeripheralRef {
+    fn drop(&mut self) {
+        unsafe { release(self.0) };
+    }
+}
+
+/// Returns a reference to the device's handle, creating it if no reference is held. `services`
+/// are added to the advertised services recorded so far, and `properties` replace the latest
+/// properties unless they could not be read.
+pub(crate) fn acquire(
+    module: &Arc<ModuleInt>,
+    peripheral: Peripheral,
+    services: Vec<Uuid>,
+    properties: Option<PeripheralProperties>,
+) -> PeripheralRef {
+    let handle = {
+        let mut handles = module.handles.lock().unwrap();
+        match handles.entries.get_mut(&peripheral.id()) {
+            Some(entry) => {
+                entry.refs += 1;
+                PeripheralRef(entry.handle)
+            }
+            None => {
+                let handle =
+                    CPeripheral::new(Arc::clone(module), peripheral.clone(), services, properties);
+                let handle = Box::into_raw(Box::new(handle));
+                let entry = Entry { handle, refs: 1 };
+                handles.entries.insert(peripheral.id(), entry);
+                return PeripheralRef(handle);
+            }
+        }
+    };
+
+    // The reference just counted keeps the handle alive
+    let peripheral = unsafe { &*handle.0 };
+    {
+        let mut advertised = peripheral.p.services.lock().unwrap();
+        for s in services {
+            if !advertised.contains(&s) {
+                advertised.push(s);
+            }
+        }
+    }
+    if properties.is_some() {
+        *peripheral.p.properties.lock().unwrap() = properties;
+    }
+    handle
+}
+
+/// Records the device's latest properties on its handle, if a reference to one is held
+pub(crate) fn refresh(module: &ModuleInt, id: &PeripheralId, properties: PeripheralProperties) {
+    let handles = module.handles.lock().unwrap();
+    if let Some(entry) = handles.entries.get(id) {
+        // The entry's reference keeps the handle alive while the lock is held
+        let peripheral = unsafe { &*entry.handle };
+        *peripheral.p.properties.lock().unwrap() = Some(properties);
+    }
+}
+
+/// Releases one reference to the handle, freeing it with the last one
+pub(crate) unsafe fn release(handle: *mut CPeripheral) {
+    let last = {
+        let peripheral = &*handle;
+        let mut handles = peripheral.module.handles.lock().unwrap();
+        let id = peripheral.p.peripheral.id();
+        match handles.entries.get_mut(&id) {
+            Some(entry) if entry.handle == handle => {
+                entry.refs -= 1;
+                let last = entry.refs == 0;
+                if last {
+                    handles.entries.remove(&id);
+                }
+                last
+            }
+            // Not a cached handle, so this was its only reference
+            _ => true,
+        }
+    };
+
+    // Freed outside the lock, since the handle can hold the last reference to the module
+    if last {
+        drop(Box::from_raw(handle));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::tests::{Fixture, ADDRESS};
+    use crate::{
+        free_peripheral, free_peripheral_list, module_get_peripheral_by_address,
+        module_get_peripherals, peripheral_get_address, SUCCESS,
+    };
+    use std::ptr::{null, null_mut};
+    use std::slice::from_raw_parts;
+
+    #[test]
+    fn each_device_keeps_one_handle() {
+        unsafe {
+            let f = Fixture::new();
+            let mut by_address = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(f.module, ADDRESS, &mut by_address)
+            );
+            assert_eq!(f.peripheral, by_address);
+
+            let mut list = null_mut();
+            let mut count = 0;
+            assert_eq!(
+                SUCCESS,
+                module_get_peripherals(f.module, null(), &mut list, &mut count)
+            );
+            assert_eq!(&[f.peripheral], from_raw_parts(list, count as usize));
+            free_peripheral_list(list, count);
+
+            // The found callback's reference is still held after the others are released
+            free_peripheral(by_address);
+            let mut address = 0;
+            assert_eq!(SUCCESS, peripheral_get_address(f.peripheral, &mut address));
+            assert_eq!(ADDRESS, address);
+        }
+    }
+}
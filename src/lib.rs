@@ -1,75 +1,219 @@
+#![allow(clippy::missing_safety_doc)]
+
+mod backend;
+mod connection;
+mod dispatch;
+mod handles;
+mod listeners;
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+mod queue;
+mod reconnect;
+mod scan;
+mod sync;
+
+use backend::{Adapter, CentralEvent, Peripheral, PeripheralId};
 use btleplug::api::{
-    BDAddr, Central, CentralEvent, CharPropFlags, Characteristic, Manager as _, Peripheral as _,
+    BDAddr, CentralState, CharPropFlags, Characteristic, Descriptor, PeripheralProperties,
     ScanFilter, WriteType,
 };
-use btleplug::platform::{Adapter, Manager, Peripheral};
 use btleplug::Error as BleError;
 use btleplug::{Error, Result as BleResult};
+use connection::{ConnectionState, Connections};
+use dispatch::Dispatcher;
 use futures::StreamExt;
+use handles::{acquire, release, Handles, PeripheralRef};
+use listeners::{add_listener, Listeners};
+use queue::{Event, EventQueue};
+use scan::{ScanRules, ScanState};
 use std::collections::{BTreeSet, HashMap};
-use std::ffi::{c_char, c_int, CString};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::future::{pending, Future};
 use std::mem::size_of;
-use std::ptr::{null, null_mut};
+use std::ptr::{null, null_mut, slice_from_raw_parts_mut};
 use std::slice::from_raw_parts;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use uuid::Uuid;
 
 use log::{debug, error, info, trace, warn, LevelFilter};
 
-const SUCCESS: c_int = 0;
-const ERROR_FAIL: c_int = 1;
-const INVALID_ARGUMENT: c_int = 2;
-const ERROR_PERMISSION_DENIED: c_int = 101;
-const ERROR_DEVICE_NOT_FOUND: c_int = 102;
-const ERROR_NOT_CONNECTED: c_int = 103;
-const ERROR_UNEXPECTED_CALLBACK: c_int = 104;
-const ERROR_UNEXPECTED_CHARACTERISTIC: c_int = 105;
-const ERROR_NO_SUCH_CHARACTERISTIC: c_int = 106;
-const ERROR_NOT_SUPPORTED: c_int = 107;
-const ERROR_TIMED_OUT: c_int = 108;
-const ERROR_UUID: c_int = 109;
-const ERROR_INVALID_BD_ADDR: c_int = 110;
-const ERROR_RUNTIME_ERROR: c_int = 111;
+/// Result codes returned by the API and passed to completion callbacks
+#[repr(C)]
+pub enum ErrorCode {
+    Success = 0,
+    ErrorFail = 1,
+    InvalidArgument = 2,
+    ErrorPermissionDenied = 101,
+    ErrorDeviceNotFound = 102,
+    ErrorNotConnected = 103,
+    ErrorUnexpectedCallback = 104,
+    ErrorUnexpectedCharacteristic = 105,
+    ErrorNoSuchCharacteristic = 106,
+    ErrorNotSupported = 107,
+    ErrorTimedOut = 108,
+    ErrorUuid = 109,
+    ErrorInvalidBdAddr = 110,
+    ErrorRuntimeError = 111,
+    ErrorCancelled = 112,
+    ErrorAlreadyScanning = 113,
+}
+
+const SUCCESS: c_int = ErrorCode::Success as c_int;
+const ERROR_FAIL: c_int = ErrorCode::ErrorFail as c_int;
+const INVALID_ARGUMENT: c_int = ErrorCode::InvalidArgument as c_int;
+const ERROR_PERMISSION_DENIED: c_int = ErrorCode::ErrorPermissionDenied as c_int;
+const ERROR_DEVICE_NOT_FOUND: c_int = ErrorCode::ErrorDeviceNotFound as c_int;
+const ERROR_NOT_CONNECTED: c_int = ErrorCode::ErrorNotConnected as c_int;
+const ERROR_UNEXPECTED_CALLBACK: c_int = ErrorCode::ErrorUnexpectedCallback as c_int;
+const ERROR_UNEXPECTED_CHARACTERISTIC: c_int = ErrorCode::ErrorUnexpectedCharacteristic as c_int;
+const ERROR_NO_SUCH_CHARACTERISTIC: c_int = ErrorCode::ErrorNoSuchCharacteristic as c_int;
+const ERROR_NOT_SUPPORTED: c_int = ErrorCode::ErrorNotSupported as c_int;
+const ERROR_TIMED_OUT: c_int = ErrorCode::ErrorTimedOut as c_int;
+const ERROR_UUID: c_int = ErrorCode::ErrorUuid as c_int;
+const ERROR_INVALID_BD_ADDR: c_int = ErrorCode::ErrorInvalidBdAddr as c_int;
+const ERROR_RUNTIME_ERROR: c_int = ErrorCode::ErrorRuntimeError as c_int;
+const ERROR_CANCELLED: c_int = ErrorCode::ErrorCancelled as c_int;
+const ERROR_ALREADY_SCANNING: c_int = ErrorCode::ErrorAlreadyScanning as c_int;
 
 type PeripheralFoundCallback = extern "C" fn(
     id: u64,
     peripheral: *mut CPeripheral,
     services: *const Uuid,
     service_count: c_int,
+    user_data: *mut c_void,
 ) -> c_int;
-type PeripheralEventCallback = extern "C" fn(id: u64);
-type CompletedCallback = extern "C" fn(result: c_int);
+type PeripheralEventCallback = extern "C" fn(id: u64, user_data: *mut c_void);
+type CompletedCallback = extern "C" fn(result: c_int, user_data: *mut c_void);
+
+/// Callbacks for adapter events, any of which may be null
+///
+/// The signatures are spelled out rather than aliased so the generated header keeps them
+/// nullable function pointers.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct EventCallbacks {
+    found: Option<
+        extern "C" fn(
+            id: u64,
+            peripheral: *mut CPeripheral,
+            services: *const Uuid,
+            service_count: c_int,
+            user_data: *mut c_void,
+        ) -> c_int,
+    >,
+    disconnected: Option<extern "C" fn(id: u64, user_data: *mut c_void)>,
+    connected: Option<extern "C" fn(id: u64, user_data: *mut c_void)>,
+    updated: Option<extern "C" fn(id: u64, user_data: *mut c_void)>,
+    manufacturer_data: Option<
+        extern "C" fn(
+            id: u64,
+            manufacturer_id: u16,
+            data: *const u8,
+            data_length: c_int,
+            user_data: *mut c_void,
+        ),
+    >,
+    service_data: Option<
+        extern "C" fn(
+            id: u64,
+            service_uuid: Uuid,
+            data: *const u8,
+            data_length: c_int,
+            user_data: *mut c_void,
+        ),
+    >,
+    /// Receives 0 for unknown, 1 for powered on and 2 for powered off
+    state_update: Option<extern "C" fn(state: c_int, user_data: *mut c_void)>,
+    /// Passed back unchanged to every callback
+    user_data: *mut c_void,
+}
+
+/// Opaque host context handed back to a callback
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+// Only `user_data` keeps these from being Send and Sync, and it is only handed back to the host
+unsafe impl Send for EventCallbacks {}
+unsafe impl Sync for EventCallbacks {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
 
 fn set_error_string(module: &*mut CModule, str: CString) {
     unsafe {
-        *(**module).module.last_error.blocking_lock() = str;
+        let m = &(**module).module;
+        *m.last_error.lock().unwrap() = str;
     }
 }
 
 fn set_error_str(module: &*mut CModule, str: &str) {
     unsafe {
-        *(**module).module.last_error.blocking_lock() = CString::new(str).unwrap();
+        let m = &(**module).module;
+        *m.last_error.lock().unwrap() = CString::new(str).unwrap();
     }
 }
 
 fn set_error(module: &*mut CModule, err: &Error) {
     unsafe {
-        *(**module).module.last_error.blocking_lock() = error_into_cstring(err);
+        let m = &(**module).module;
+        *m.last_error.lock().unwrap() = error_into_cstring(err);
     }
 }
 
 fn set_peripheral_error_str(peripheral: &*mut CPeripheral, str: &str) {
     unsafe {
-        *(**peripheral).p.last_error.blocking_lock() = CString::new(str).unwrap();
+        let p = &(**peripheral).p;
+        *p.last_error.lock().unwrap() = CString::new(str).unwrap();
+    }
+}
+
+const IN_CALLBACK: &str = "Blocking functions cannot be called from a callback";
+
+/// Blocking on the runtime from one of its own threads panics, which would abort the host, so
+/// blocking functions fail with `ERROR_RUNTIME_ERROR` when called from a callback
+fn in_callback() -> bool {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        error!("blocking call from a runtime thread");
+        return true;
     }
+    false
 }
 
 struct ModuleInt {
-    last_error: Mutex<CString>,
+    last_error: std::sync::Mutex<CString>,
     runtime: Option<Runtime>,
     adapter: Option<Adapter>,
+    events: Arc<EventQueue>,
+    connections: Arc<Connections>,
+    scan: std::sync::Mutex<ScanState>,
+    /// Callbacks receiving the adapter's events. Locked by the host thread and for each event,
+    /// never across an await.
+    listeners: std::sync::Mutex<Listeners>,
+    /// The handle for each device the host holds a reference to
+    handles: std::sync::Mutex<Handles>,
+}
+
+impl Drop for ModuleInt {
+    fn drop(&mut self) {
+        // The last reference can be released by a task on the module's own runtime, which is
+        // not allowed to block waiting for the runtime to shut down
+        if let Some(runtime) = self.runtime.take() {
+            if tokio::runtime::Handle::try_current().is_ok() {
+                runtime.shutdown_background();
+            }
+        }
+    }
 }
 
 pub struct CModule {
@@ -78,20 +222,56 @@ pub struct CModule {
 
 impl CModule {
     fn new(runtime: Option<Runtime>, adapter: Option<Adapter>) -> CModule {
-        CModule {
-            module: Arc::new(ModuleInt {
-                runtime,
-                adapter,
-                last_error: Mutex::new(CString::default()),
-            }),
+        let module = Arc::new(ModuleInt {
+            runtime,
+            adapter,
+            events: Arc::new(EventQueue::new()),
+            connections: Arc::new(Connections::default()),
+            scan: std::sync::Mutex::new(ScanState::default()),
+            listeners: std::sync::Mutex::new(Listeners::default()),
+            handles: std::sync::Mutex::new(Handles::default()),
+            last_error: std::sync::Mutex::new(CString::default()),
+        });
+        // Connection callbacks and reconnection follow the adapter's events, so they are read
+        // whether or not the host listens to them
+        if let (Some(runtime), Some(_)) = (&module.runtime, &module.adapter) {
+            runtime.spawn(event_loop(Arc::clone(&module)));
         }
+        CModule { module }
+    }
+}
+
+impl Drop for CModule {
+    fn drop(&mut self) {
+        self.module.events.clear();
     }
 }
 
 struct PeripheralHandle {
     peripheral: Peripheral,
-    services: Vec<Uuid>,
-    last_error: Mutex<CString>,
+    /// Services advertised by the peripheral so far
+    services: std::sync::Mutex<Vec<Uuid>>,
+    /// Advertisement properties as of the latest event or lookup, read by
+    /// `peripheral_get_properties` without blocking on the adapter
+    properties: std::sync::Mutex<Option<PeripheralProperties>>,
+    last_error: std::sync::Mutex<CString>,
+    events: Arc<EventQueue>,
+    connections: Arc<Connections>,
+    /// The service of each characteristic, for notifications
+    characteristic_services: ServiceIndex,
+    /// Routes notifications to the handlers registered with `peripheral_subscribe_with_callback`
+    dispatcher: Mutex<Option<Dispatcher>>,
+    /// The task started by `peripheral_register_notification_events`. Swapped from the calling
+    /// thread, which may not block on an async lock.
+    listener: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for PeripheralHandle {
+    fn drop(&mut self) {
+        if let Some(listener) = self.listener.get_mut().unwrap().take() {
+            listener.abort();
+        }
+    }
 }
 
 pub struct CPeripheral {
@@ -121,37 +301,112 @@ pub struct CharacteristicDescriptorDescriptor {
     uuid: Uuid,
 }
 
+#[repr(C)]
+pub struct ManufacturerDataDescriptor {
+    manufacturer_id: u16,
+    data: *mut u8,
+    data_length: c_int,
+}
+
+#[repr(C)]
+pub struct ServiceDataDescriptor {
+    uuid: Uuid,
+    data: *mut u8,
+    data_length: c_int,
+}
+
+#[repr(C)]
+pub struct PeripheralPropertiesDescriptor {
+    address: u64,
+    /// 0 when unknown, 1 for public and 2 for random addresses
+    address_type: c_int,
+    /// Null when the peripheral did not advertise a name
+    local_name: *mut c_char,
+    has_tx_power_level: bool,
+    tx_power_level: i16,
+    has_rssi: bool,
+    rssi: i16,
+    manufacturer_data: *mut ManufacturerDataDescriptor,
+    manufacturer_data_count: c_int,
+    service_data: *mut ServiceDataDescriptor,
+    service_data_count: c_int,
+    services: *mut Uuid,
+    service_count: c_int,
+}
+
 impl CPeripheral {
-    fn new(module: Arc<ModuleInt>, peripheral: Peripheral, services: Vec<Uuid>) -> CPeripheral {
+    fn new(
+        module: Arc<ModuleInt>,
+        peripheral: Peripheral,
+        services: Vec<Uuid>,
+        properties: Option<PeripheralProperties>,
+    ) -> CPeripheral {
+        let events = Arc::clone(&module.events);
+        let connections = Arc::clone(&module.connections);
+        let characteristic_services = ServiceIndex::new(&peripheral);
         CPeripheral {
             module,
             p: Arc::new(PeripheralHandle {
                 peripheral,
-                services,
-                last_error: Mutex::new(CString::default()),
+                services: std::sync::Mutex::new(services),
+                properties: std::sync::Mutex::new(properties),
+                last_error: std::sync::Mutex::new(CString::default()),
+                events,
+                connections,
+                characteristic_services,
+                dispatcher: Mutex::new(None),
+                listener: std::sync::Mutex::new(None),
             }),
         }
     }
 }
 
-async fn get_central(manager: &Manager) -> BleResult<Adapter> {
-    let adapters = manager.adapters().await?;
-    match adapters.into_iter().nth(0) {
-        None => Err(BleError::RuntimeError(String::from("No adapters found"))),
-        Some(a) => Ok(a),
+enum AdapterSelector {
+    Index(usize),
+    Name(String),
+}
+
+async fn get_central(selector: &AdapterSelector) -> BleResult<Adapter> {
+    let adapters = backend::adapters().await?;
+    if adapters.is_empty() {
+        return Err(BleError::RuntimeError(String::from("No adapters found")));
+    }
+
+    match selector {
+        AdapterSelector::Index(index) => match adapters.into_iter().nth(*index) {
+            None => Err(BleError::RuntimeError(format!(
+                "No adapter found at index {index}"
+            ))),
+            Some(a) => Ok(a),
+        },
+        AdapterSelector::Name(name) => {
+            for a in adapters {
+                let info = a.adapter_info().await?;
+                debug!("Checking adapter {info} against {name}");
+                if info == *name {
+                    return Ok(a);
+                }
+            }
+            Err(BleError::RuntimeError(format!(
+                "No adapter found matching {name}"
+            )))
+        }
     }
 }
 
-async fn get_manager() -> BleResult<Adapter> {
-    let manager = Manager::new().await?;
-    get_central(&manager).await
+async fn get_adapter_infos() -> BleResult<Vec<String>> {
+    let mut infos = Vec::new();
+    for a in backend::adapters().await? {
+        infos.push(a.adapter_info().await?);
+    }
+    Ok(infos)
 }
 
-unsafe fn error_into_cstring(e: &Error) -> CString {
+fn error_into_cstring(e: &Error) -> CString {
     CString::new(e.to_string()).unwrap_or(CString::new("Unknown error").unwrap())
 }
 
-unsafe fn error_to_result(e: &Error) -> c_int {
+fn error_to_result(e: &Error) -> c_int {
     match e {
         Error::PermissionDenied => ERROR_PERMISSION_DENIED,
         Error::DeviceNotFound => ERROR_DEVICE_NOT_FOUND,
@@ -168,7 +423,7 @@ unsafe fn error_to_result(e: &Error) -> c_int {
     }
 }
 
-unsafe fn get_long_addr(a: BDAddr) -> u64 {
+fn get_long_addr(a: BDAddr) -> u64 {
     let addr = a.into_inner();
     let mut lbytes = [0u8; 8];
     lbytes[2..].copy_from_slice(&addr);
@@ -188,9 +443,98 @@ pub extern "C" fn set_log_level(level: c_int) {
     });
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn list_adapters(
+    adapters: *mut *mut *mut c_char,
+    count: *mut c_int,
+) -> c_int {
+    trace!("Enter: list_adapters");
+    if adapters.is_null() || count.is_null() {
+        error!("null output argument");
+        return INVALID_ARGUMENT;
+    }
+    *adapters = null_mut();
+    *count = 0;
+
+    let runtime = match Runtime::new() {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Failed to initialize tokio::Runtime {:?}", e);
+            return ERROR_FAIL;
+        }
+    };
+
+    let infos = match runtime.block_on(get_adapter_infos()) {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Failed to enumerate adapters {:?}", e);
+            return error_to_result(&e);
+        }
+    };
+
+    info!("Found {} adapters", infos.len());
+    (*adapters, *count) = into_raw_slice(
+        infos
+            .into_iter()
+            .map(|i| {
+                CString::new(i)
+                    .unwrap_or(CString::new("Unknown adapter").unwrap())
+                    .into_raw()
+            })
+            .collect(),
+    );
+    trace!("Success: list_adapters");
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free_adapter_list(adapters: *mut *mut c_char, count: c_int) -> c_int {
+    if adapters.is_null() {
+        return SUCCESS;
+    }
+
+    for s in free_raw_slice(adapters, count).iter() {
+        free_string(*s);
+    }
+    SUCCESS
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn create_module(module: *mut *mut CModule) -> c_int {
     trace!("Enter: create_module");
+    create_module_for(module, AdapterSelector::Index(0))
+}
+
+/// Binds the module to the adapter whose name, as returned by `list_adapters`, is `adapter_name`,
+/// or to the adapter at `adapter_index` when the name is null
+#[no_mangle]
+pub unsafe extern "C" fn create_module_with_adapter(
+    module: *mut *mut CModule,
+    adapter_index: c_int,
+    adapter_name: *const c_char,
+) -> c_int {
+    trace!("Enter: create_module_with_adapter");
+    let selector = if !adapter_name.is_null() {
+        match CStr::from_ptr(adapter_name).to_str() {
+            Ok(name) => AdapterSelector::Name(name.to_string()),
+            Err(e) => {
+                error!("Invalid adapter name: {:?}", e);
+                *module = null_mut();
+                return INVALID_ARGUMENT;
+            }
+        }
+    } else if adapter_index >= 0 {
+        AdapterSelector::Index(adapter_index as usize)
+    } else {
+        error!("Invalid adapter index: {adapter_index}");
+        *module = null_mut();
+        return INVALID_ARGUMENT;
+    };
+
+    create_module_for(module, selector)
+}
+
+unsafe fn create_module_for(module: *mut *mut CModule, selector: AdapterSelector) -> c_int {
     *module = null_mut();
 
     let runtime = match Runtime::new() {
@@ -204,7 +548,7 @@ pub unsafe extern "C" fn create_module(module: *mut *mut CModule) -> c_int {
     };
 
     debug!("Initializing adapter with runtime");
-    let adapter = match runtime.block_on(get_manager()) {
+    let adapter = match runtime.block_on(get_central(&selector)) {
         Ok(a) => a,
         Err(e) => {
             warn!("Failed to initialize Adapter {:?}", e);
@@ -224,117 +568,277 @@ pub unsafe extern "C" fn set_event_callbacks(
     module: *mut CModule,
     found: PeripheralFoundCallback,
     disconnected: PeripheralEventCallback,
+    user_data: *mut c_void,
 ) -> c_int {
     trace!("Enter: set_event_callbacks");
-    if module.is_null() {
-        error!("null module");
-        return INVALID_ARGUMENT;
-    }
+    let callbacks = EventCallbacks {
+        found: Some(found),
+        disconnected: Some(disconnected),
+        connected: None,
+        updated: None,
+        manufacturer_data: None,
+        service_data: None,
+        state_update: None,
+        user_data,
+    };
+    set_event_callbacks_ex(module, &callbacks)
+}
 
-    let m = &(*module).module;
-    if m.adapter.is_none() || m.runtime.is_none() {
-        error!("null adapter/runtime");
-        set_error_str(&module, "Invalid module");
-        return INVALID_ARGUMENT;
+/// Sets the module's event callbacks, replacing those from an earlier call. Listeners added with
+/// `module_add_event_listener` are unaffected.
+#[no_mangle]
+pub unsafe extern "C" fn set_event_callbacks_ex(
+    module: *mut CModule,
+    callbacks: *const EventCallbacks,
+) -> c_int {
+    trace!("Enter: set_event_callbacks_ex");
+    match add_listener(module, callbacks, Listeners::replace_primary) {
+        Ok(_) => {
+            trace!("Success: set_event_callbacks_ex");
+            SUCCESS
+        }
+        Err(code) => code,
     }
+}
 
-    let runtime = m.runtime.as_ref().unwrap();
-
-    let m = (*module).module.clone();
-
-    runtime.spawn(async move {
-        let adapter = m.adapter.as_ref().unwrap();
-        let mut events = adapter.events().await?;
-        let weak = Arc::downgrade(&m);
-        drop(m);
-
-        debug!("Starting scan");
-        let mut device_map = HashMap::new();
-
-        while let Some(event) = events.next().await {
-            match event {
-                CentralEvent::DeviceDiscovered(id) => {
-                    debug!("Device discovered: {:?}", id);
-                    let l_mod = match weak.upgrade() {
-                        None => {
-                            break;
-                        }
-                        Some(a) => a,
-                    };
-                    let adapter = l_mod.adapter.as_ref().unwrap();
-                    match adapter.peripheral(&id).await {
-                        Ok(p) => {
-                            info!("Sending peripheral {:?}", id);
-                            let raw = Box::into_raw(Box::new(CPeripheral::new(
-                                Arc::clone(&l_mod),
-                                p,
-                                Vec::default(),
-                            )));
-                            let addr = get_long_addr((*raw).p.peripheral.address());
-                            device_map.insert(id, addr);
-                            if 0 == found(addr, raw, null(), 0) {
-                                // The handle was rejected, drop it
-                                free_ptr(raw);
-                            }
+/// Reads the adapter's events for as long as the module lives, handing each one to every
+/// listener registered at the time
+async fn event_loop(m: Arc<ModuleInt>) -> BleResult<()> {
+    let adapter = m.adapter.as_ref().unwrap();
+    let mut events = adapter.events().await?;
+    let weak = Arc::downgrade(&m);
+    drop(m);
+
+    debug!("Starting scan");
+    let mut device_map = HashMap::new();
+    let mut advertised_services: HashMap<PeripheralId, Vec<Uuid>> = HashMap::new();
+    let mut last_found = HashMap::new();
+
+    while let Some(event) = events.next().await {
+        let l_mod = match weak.upgrade() {
+            None => {
+                break;
+            }
+            Some(a) => a,
+        };
+        let adapter = l_mod.adapter.as_ref().unwrap();
+        let listeners = l_mod.listeners.lock().unwrap().callbacks();
+        match event {
+            CentralEvent::DeviceDiscovered(id) => {
+                debug!("Device discovered: {:?}", id);
+                match adapter.peripheral(&id).await {
+                    Ok(p) => {
+                        info!("Sending peripheral {:?}", id);
+                        let services = advertised_services.get(&id).cloned().unwrap_or_default();
+                        let addr = get_long_addr(p.address());
+                        device_map.insert(id, addr);
+                        let properties = p.properties().await.ok().flatten();
+                        let rules = Arc::clone(&l_mod.scan.lock().unwrap().rules);
+                        if !rules.admit(properties.as_ref(), addr, &mut last_found) {
+                            continue;
                         }
-                        Err(e) => {
-                            error!("Failed to find discovered device for {:#}, {:?}", id, e);
+                        for callbacks in &listeners {
+                            let handle =
+                                acquire(&l_mod, p.clone(), services.clone(), properties.clone());
+                            l_mod.events.deliver(
+                                UserData(callbacks.user_data),
+                                Event::Found {
+                                    callback: callbacks.found,
+                                    id: addr,
+                                    peripheral: handle,
+                                    services: services.clone(),
+                                },
+                            );
                         }
                     }
+                    Err(e) => {
+                        error!("Failed to find discovered device for {:#}, {:?}", id, e);
+                    }
+                }
+            }
+            CentralEvent::ServicesAdvertisement { id, services } => {
+                debug!("Services discovered: {:?} : {:?}", id, services);
+                let known = advertised_services.entry(id.clone()).or_default();
+                for s in services {
+                    if !known.contains(&s) {
+                        known.push(s);
+                    }
                 }
-                CentralEvent::ServicesAdvertisement { id, services } => {
-                    debug!("Services discovered: {:?} : {:?}", id, services);
-                    let l_mod = match weak.upgrade() {
-                        None => {
-                            break;
+                match adapter.peripheral(&id).await {
+                    Ok(p) => {
+                        let addr = get_long_addr(p.address());
+                        device_map.insert(id, addr);
+                        let properties = p.properties().await.ok().flatten();
+                        let rules = Arc::clone(&l_mod.scan.lock().unwrap().rules);
+                        if !rules.admit(properties.as_ref(), addr, &mut last_found) {
+                            continue;
                         }
-                        Some(a) => a,
-                    };
-                    let adapter = l_mod.adapter.as_ref().unwrap();
-                    match adapter.peripheral(&id).await {
-                        Ok(p) => {
-                            let raw = Box::into_raw(Box::new(CPeripheral::new(
-                                Arc::clone(&l_mod),
-                                p,
-                                Vec::default(),
-                            )));
-                            let addr = get_long_addr((*raw).p.peripheral.address());
-                            device_map.insert(id, addr);
-                            if 0 == found(
-                                addr,
-                                raw,
-                                (*raw).p.services.as_ptr(),
-                                (*raw).p.services.len() as c_int,
-                            ) {
-                                // The handle was rejected, drop it
-                                free_ptr(raw);
-                            }
+                        for callbacks in &listeners {
+                            let handle =
+                                acquire(&l_mod, p.clone(), known.clone(), properties.clone());
+                            l_mod.events.deliver(
+                                UserData(callbacks.user_data),
+                                Event::Found {
+                                    callback: callbacks.found,
+                                    id: addr,
+                                    peripheral: handle,
+                                    services: known.clone(),
+                                },
+                            );
                         }
-                        Err(e) => {
-                            error!("Failed to find discovered device for {:#}, {:?}", id, e);
+                    }
+                    Err(e) => {
+                        error!("Failed to find discovered device for {:#}, {:?}", id, e);
+                    }
+                }
+            }
+            CentralEvent::ManufacturerDataAdvertisement {
+                id,
+                manufacturer_data,
+            } => {
+                debug!("Manufacturer data: {:?} : {:?}", id, manufacturer_data);
+                refresh_properties(&l_mod, &id).await;
+                if let Some(addr) = lookup_address(adapter, &mut device_map, &id).await {
+                    for (manufacturer_id, data) in manufacturer_data {
+                        for callbacks in &listeners {
+                            l_mod.events.deliver(
+                                UserData(callbacks.user_data),
+                                Event::ManufacturerData {
+                                    callback: callbacks.manufacturer_data,
+                                    id: addr,
+                                    manufacturer_id,
+                                    data: data.clone(),
+                                },
+                            );
                         }
                     }
                 }
-                CentralEvent::DeviceDisconnected(id) => {
-                    info!("Device disconnected : {:?}", id);
-                    match device_map.get(&id) {
-                        Some(addr) => {
-                            disconnected(*addr);
+            }
+            CentralEvent::ServiceDataAdvertisement { id, service_data } => {
+                debug!("Service data: {:?} : {:?}", id, service_data);
+                refresh_properties(&l_mod, &id).await;
+                if let Some(addr) = lookup_address(adapter, &mut device_map, &id).await {
+                    for (service_uuid, data) in service_data {
+                        for callbacks in &listeners {
+                            l_mod.events.deliver(
+                                UserData(callbacks.user_data),
+                                Event::ServiceData {
+                                    callback: callbacks.service_data,
+                                    id: addr,
+                                    service_uuid,
+                                    data: data.clone(),
+                                },
+                            );
                         }
-                        None => {
-                            warn!("Disconnect from unrecognized peripheral: {:?}", id);
+                    }
+                }
+            }
+            CentralEvent::DeviceUpdated(id) => {
+                trace!("Device updated: {:?}", id);
+                refresh_properties(&l_mod, &id).await;
+                if let Some(addr) = lookup_address(adapter, &mut device_map, &id).await {
+                    for callbacks in &listeners {
+                        let callback = callbacks.updated;
+                        let user_data = UserData(callbacks.user_data);
+                        l_mod
+                            .events
+                            .deliver(user_data, Event::Updated { callback, id: addr });
+                    }
+                }
+            }
+            CentralEvent::DeviceConnected(id) => {
+                info!("Device connected : {:?}", id);
+                if let Some(addr) = lookup_address(adapter, &mut device_map, &id).await {
+                    refresh_connection(&l_mod, &id, addr).await;
+                    for callbacks in &listeners {
+                        let callback = callbacks.connected;
+                        let user_data = UserData(callbacks.user_data);
+                        l_mod
+                            .events
+                            .deliver(user_data, Event::Connected { callback, id: addr });
+                    }
+                }
+            }
+            CentralEvent::DeviceDisconnected(id) => {
+                info!("Device disconnected : {:?}", id);
+                match lookup_address(adapter, &mut device_map, &id).await {
+                    Some(addr) => {
+                        refresh_connection(&l_mod, &id, addr).await;
+                        for callbacks in &listeners {
+                            let callback = callbacks.disconnected;
+                            let user_data = UserData(callbacks.user_data);
+                            l_mod
+                                .events
+                                .deliver(user_data, Event::Disconnected { callback, id: addr });
                         }
                     }
+                    None => {
+                        warn!("Disconnect from unrecognized peripheral: {:?}", id);
+                    }
+                }
+            }
+            CentralEvent::StateUpdate(state) => {
+                info!("Adapter state changed : {:?}", state);
+                if state != CentralState::PoweredOn {
+                    l_mod.scan.lock().unwrap().stop();
+                }
+                let state = state as c_int;
+                for callbacks in &listeners {
+                    let callback = callbacks.state_update;
+                    let user_data = UserData(callbacks.user_data);
+                    l_mod
+                        .events
+                        .deliver(user_data, Event::StateUpdate { callback, state });
                 }
-                _ => {}
             }
         }
-        info!("Event listening ended!");
-        Ok::<(), BleError>(())
-    });
+    }
+    info!("Event listening ended!");
+    Ok(())
+}
 
-    trace!("Success: set_event_callbacks");
-    SUCCESS
+/// Records the device's current properties on its handle, if one is held, so the callbacks for
+/// this event read them through `peripheral_get_properties`
+async fn refresh_properties(module: &ModuleInt, id: &PeripheralId) {
+    if !module.handles.lock().unwrap().contains(id) {
+        return;
+    }
+    if let Ok(p) = module.adapter.as_ref().unwrap().peripheral(id).await {
+        if let Ok(Some(properties)) = p.properties().await {
+            handles::refresh(module, id, properties);
+        }
+    }
+}
+
+async fn refresh_connection(module: &ModuleInt, id: &PeripheralId, address: u64) {
+    if let Ok(p) = module.adapter.as_ref().unwrap().peripheral(id).await {
+        module
+            .connections
+            .refresh(&module.events, &p, address)
+            .await;
+    }
+}
+
+async fn lookup_address(
+    adapter: &Adapter,
+    device_map: &mut HashMap<PeripheralId, u64>,
+    id: &PeripheralId,
+) -> Option<u64> {
+    if let Some(addr) = device_map.get(id) {
+        return Some(*addr);
+    }
+
+    match adapter.peripheral(id).await {
+        Ok(p) => {
+            let addr = get_long_addr(p.address());
+            device_map.insert(id.clone(), addr);
+            Some(addr)
+        }
+        Err(e) => {
+            error!("Failed to find device for {:#}, {:?}", id, e);
+            None
+        }
+    }
 }
 
 #[no_mangle]
@@ -343,40 +847,37 @@ pub unsafe extern "C" fn start_scan_peripherals(
     service_uuids: *mut Uuid,
     service_uuid_count: i32,
 ) -> c_int {
-    trace!("Enter: peripheral_is_connected");
+    trace!("Enter: start_scan_peripherals");
     if module.is_null() {
         error!("null module");
         return INVALID_ARGUMENT;
     }
 
-    let m = &(*module).module;
-    if m.adapter.is_none() || m.runtime.is_none() {
-        error!("null adapter/runtime");
-        set_error_str(&module, "Invalid module");
-        return INVALID_ARGUMENT;
+    match scan_filter(module, service_uuids, service_uuid_count) {
+        Ok(filter) => start_scan(module, filter, ScanRules::default(), None),
+        Err(code) => code,
     }
+}
 
-    let runtime = m.runtime.as_ref().unwrap();
-    let adapter = m.adapter.as_ref().unwrap();
-
-    let filter = match service_uuid_count {
+unsafe fn scan_filter(
+    module: *mut CModule,
+    service_uuids: *const Uuid,
+    service_uuid_count: c_int,
+) -> Result<ScanFilter, c_int> {
+    match service_uuid_count {
         0 => {
             debug!("No filters applied");
-            ScanFilter::default()
+            Ok(ScanFilter::default())
         }
         1..=100 => {
             if service_uuids.is_null() {
                 set_error_str(&module, "Null argument: service_uuids");
-                return INVALID_ARGUMENT;
-            }
-
-            let mut v = Vec::new();
-            for i in 0..service_uuid_count {
-                v.push(*service_uuids.offset(i as isize));
+                return Err(INVALID_ARGUMENT);
             }
 
+            let v = from_raw_parts(service_uuids, service_uuid_count as usize).to_vec();
             debug!("Applying filters to scan: {:?}", v);
-            ScanFilter { services: v }
+            Ok(ScanFilter { services: v })
         }
         _ => {
             error!("Invalid number of filters provided: {service_uuid_count}");
@@ -384,23 +885,64 @@ pub unsafe extern "C" fn start_scan_peripherals(
                 &module,
                 "Out of range: service_uuid_count must be in range 1..100",
             );
-            return ERROR_FAIL;
+            Err(ERROR_FAIL)
         }
-    };
+    }
+}
+
+/// Starts scanning, with `rules` applied by the event loop to peripherals the scan reports. A
+/// `timer` is signalled when the scan is stopped before it expires.
+unsafe fn start_scan(
+    module: *mut CModule,
+    filter: ScanFilter,
+    rules: ScanRules,
+    timer: Option<Arc<Notify>>,
+) -> c_int {
+    let m = &(*module).module;
+    if m.adapter.is_none() || m.runtime.is_none() {
+        error!("null adapter/runtime");
+        set_error_str(&module, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    if in_callback() {
+        set_error_str(&module, IN_CALLBACK);
+        return ERROR_RUNTIME_ERROR;
+    }
+
+    let runtime = m.runtime.as_ref().unwrap();
+    let adapter = m.adapter.as_ref().unwrap();
+    {
+        // Marked as running before it starts, so the event loop applies the new rules to the
+        // first peripherals reported and a concurrent start fails
+        let mut scan = m.scan.lock().unwrap();
+        if scan.scanning {
+            error!("Scan already running");
+            set_error_str(&module, "Already scanning");
+            return ERROR_ALREADY_SCANNING;
+        }
+        scan.rules = Arc::new(rules);
+        scan.scanning = true;
+        scan.timer = timer;
+    }
 
     match runtime.block_on(adapter.start_scan(filter)) {
         Ok(_) => {
-            trace!("Success: start_scan_peripherals");
+            trace!("Success: start_scan");
             SUCCESS
         }
         Err(e) => {
             error!("Error start_scan: {:?}", e);
+            let mut scan = m.scan.lock().unwrap();
+            scan.scanning = false;
+            scan.timer = None;
             set_error(&module, &e);
             error_to_result(&e)
         }
     }
 }
 
+/// Stops the running scan. The scan is still running if the adapter fails to stop it.
 #[no_mangle]
 pub unsafe extern "C" fn stop_scan_peripherals(module: *mut CModule) -> c_int {
     trace!("Enter: stop_scan_peripherals");
@@ -417,67 +959,657 @@ pub unsafe extern "C" fn stop_scan_peripherals(module: *mut CModule) -> c_int {
         return INVALID_ARGUMENT;
     }
 
+    if in_callback() {
+        set_error_str(&module, IN_CALLBACK);
+        return ERROR_RUNTIME_ERROR;
+    }
+
     let runtime = m.runtime.as_ref().unwrap();
     let adapter = m.adapter.as_ref().unwrap();
 
-    match runtime.block_on(adapter.stop_scan()) {
-        Err(e) => {
-            error!("error in stop_scan: {:?}", e);
-            set_error(&module, &e);
-            return error_to_result(&e);
-        }
-        _ => {}
-    };
+    if let Err(e) = runtime.block_on(adapter.stop_scan()) {
+        error!("error in stop_scan: {:?}", e);
+        set_error(&module, &e);
+        return error_to_result(&e);
+    }
+    m.scan.lock().unwrap().stop();
 
     trace!("Success: stop_scan_peripherals");
     SUCCESS
 }
 
+/// Describes the adapter the module is bound to, released with `free_string`
 #[no_mangle]
-pub unsafe extern "C" fn peripheral_get_id(
-    peripheral: *mut CPeripheral,
-    id: *mut *const c_char,
+pub unsafe extern "C" fn module_get_adapter_info(
+    module: *mut CModule,
+    info: *mut *mut c_char,
 ) -> c_int {
-    if peripheral.is_null() {
-        *id = null();
+    trace!("Enter: module_get_adapter_info");
+    if module.is_null() || info.is_null() {
+        error!("null module/info");
         return INVALID_ARGUMENT;
     }
+    *info = null_mut();
 
-    let p = &(*peripheral).p;
-    let id_str = CString::new(p.peripheral.id().to_string()).unwrap();
-    *id = id_str.into_raw();
-    SUCCESS
-}
-
-#[no_mangle]
-pub unsafe extern "C" fn peripheral_get_address(
-    peripheral: *mut CPeripheral,
-    address: *mut u64,
-) -> c_int {
-    if peripheral.is_null() {
-        *address = 0;
+    let m = &(*module).module;
+    if m.adapter.is_none() || m.runtime.is_none() {
+        error!("null adapter/runtime");
+        set_error_str(&module, "Invalid module");
         return INVALID_ARGUMENT;
     }
 
-    let p = &(*peripheral).p;
-    *address = get_long_addr(p.peripheral.address());
-    SUCCESS
-}
+    if in_callback() {
+        set_error_str(&module, IN_CALLBACK);
+        return ERROR_RUNTIME_ERROR;
+    }
 
-type IsConnectedCallback = extern "C" fn(result: c_int, connected: c_int);
+    let runtime = m.runtime.as_ref().unwrap();
+    let adapter = m.adapter.as_ref().unwrap();
+    match runtime.block_on(adapter.adapter_info()) {
+        Ok(i) => {
+            *info = CString::new(i)
+                .unwrap_or(CString::new("Unknown adapter").unwrap())
+                .into_raw();
+            SUCCESS
+        }
+        Err(e) => {
+            error!("error in adapter_info: {:?}", e);
+            set_error(&module, &e);
+            error_to_result(&e)
+        }
+    }
+}
 
+/// Reports whether the adapter is powered, using the values passed to the `state_update`
+/// callback: 0 for unknown, 1 for powered on and 2 for powered off
 #[no_mangle]
-pub unsafe extern "C" fn peripheral_is_connected(
-    peripheral: *mut CPeripheral,
-    completed_callback: IsConnectedCallback,
+pub unsafe extern "C" fn module_get_adapter_state(
+    module: *mut CModule,
+    state: *mut c_int,
 ) -> c_int {
-    trace!("Enter: peripheral_is_connected");
-    if peripheral.is_null() {
-        error!("null peripheral handle");
+    trace!("Enter: module_get_adapter_state");
+    if module.is_null() || state.is_null() {
+        error!("null module/state");
         return INVALID_ARGUMENT;
     }
+    *state = 0;
 
-    let m = &(*peripheral).module;
+    let m = &(*module).module;
+    if m.adapter.is_none() || m.runtime.is_none() {
+        error!("null adapter/runtime");
+        set_error_str(&module, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    if in_callback() {
+        set_error_str(&module, IN_CALLBACK);
+        return ERROR_RUNTIME_ERROR;
+    }
+
+    let runtime = m.runtime.as_ref().unwrap();
+    let adapter = m.adapter.as_ref().unwrap();
+    match runtime.block_on(adapter.adapter_state()) {
+        Ok(s) => {
+            *state = s as c_int;
+            SUCCESS
+        }
+        Err(e) => {
+            error!("error in adapter_state: {:?}", e);
+            set_error(&module, &e);
+            error_to_result(&e)
+        }
+    }
+}
+
+/// Opens a handle for a peripheral the adapter already knows about, from an earlier scan or the
+/// system's cache, without scanning again. Fails with `ERROR_DEVICE_NOT_FOUND` otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn module_get_peripheral_by_address(
+    module: *mut CModule,
+    address: u64,
+    peripheral: *mut *mut CPeripheral,
+) -> c_int {
+    trace!("Enter: module_get_peripheral_by_address");
+    open_peripheral(module, peripheral, Lookup::Address(address))
+}
+
+/// Like `module_get_peripheral_by_address`, matching the id reported by `peripheral_get_id`
+#[no_mangle]
+pub unsafe extern "C" fn module_get_peripheral_by_id(
+    module: *mut CModule,
+    id: *const c_char,
+    peripheral: *mut *mut CPeripheral,
+) -> c_int {
+    trace!("Enter: module_get_peripheral_by_id");
+    if module.is_null() {
+        error!("null module");
+        return INVALID_ARGUMENT;
+    }
+    if id.is_null() {
+        set_error_str(&module, "Null argument: id");
+        return INVALID_ARGUMENT;
+    }
+
+    let Ok(id) = CStr::from_ptr(id).to_str() else {
+        set_error_str(&module, "Invalid argument: id is not valid UTF-8");
+        return INVALID_ARGUMENT;
+    };
+    open_peripheral(module, peripheral, Lookup::Id(id))
+}
+
+/// How `open_peripheral` finds the device
+enum Lookup<'a> {
+    Address(u64),
+    Id(&'a str),
+}
+
+unsafe fn open_peripheral(
+    module: *mut CModule,
+    peripheral: *mut *mut CPeripheral,
+    lookup: Lookup,
+) -> c_int {
+    if module.is_null() || peripheral.is_null() {
+        error!("null module/peripheral");
+        return INVALID_ARGUMENT;
+    }
+    *peripheral = null_mut();
+
+    let m = &(*module).module;
+    if m.adapter.is_none() || m.runtime.is_none() {
+        error!("null adapter/runtime");
+        set_error_str(&module, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    if in_callback() {
+        set_error_str(&module, IN_CALLBACK);
+        return ERROR_RUNTIME_ERROR;
+    }
+
+    let runtime = m.runtime.as_ref().unwrap();
+    let found = runtime.block_on(async {
+        let adapter = m.adapter.as_ref().unwrap();
+        let p = match lookup {
+            Lookup::Address(address) => adapter
+                .peripherals()
+                .await?
+                .into_iter()
+                .find(|p| get_long_addr(p.address()) == address)
+                .ok_or(Error::DeviceNotFound)?,
+            Lookup::Id(id) => adapter.peripheral_by_id(id).await?,
+        };
+        let properties = p.properties().await?;
+        let services = advertised_services(properties.as_ref());
+        Ok::<_, Error>(acquire(m, p, services, properties))
+    });
+
+    match found {
+        Ok(handle) => {
+            *peripheral = handle.into_raw();
+            SUCCESS
+        }
+        Err(e) => {
+            warn!("Failed to open peripheral: {:?}", e);
+            set_error(&module, &e);
+            error_to_result(&e)
+        }
+    }
+}
+
+fn advertised_services(properties: Option<&PeripheralProperties>) -> Vec<Uuid> {
+    properties.map_or(Vec::new(), |p| p.services.clone())
+}
+
+/// Every peripheral the adapter knows about, with its properties. Peripherals whose properties
+/// cannot be read are left out rather than failing the others.
+async fn known_peripherals(
+    adapter: &Adapter,
+) -> BleResult<Vec<(Peripheral, Option<PeripheralProperties>)>> {
+    let mut known = Vec::new();
+    for p in adapter.peripherals().await? {
+        match p.properties().await {
+            Ok(properties) => known.push((p, properties)),
+            Err(e) => warn!("Skipping {:?}, properties unavailable: {:#}", p.id(), e),
+        }
+    }
+    Ok(known)
+}
+
+/// Restricts `module_get_peripherals` to matching peripherals
+#[repr(C)]
+pub struct PeripheralFilter {
+    /// 0 matches any peripheral, 1 only connected and 2 only disconnected ones
+    connection_state: c_int,
+    /// Unless nil, only peripherals advertising this service match
+    service_uuid: Uuid,
+}
+
+/// Opens a handle for each peripheral the adapter knows about, or only for those matching
+/// `filter` when it is not null. Release the list with `free_peripheral_list`.
+#[no_mangle]
+pub unsafe extern "C" fn module_get_peripherals(
+    module: *mut CModule,
+    filter: *const PeripheralFilter,
+    peripherals: *mut *mut *mut CPeripheral,
+    count: *mut c_int,
+) -> c_int {
+    trace!("Enter: module_get_peripherals");
+    if module.is_null() || peripherals.is_null() || count.is_null() {
+        error!("null module/output argument");
+        return INVALID_ARGUMENT;
+    }
+    *peripherals = null_mut();
+    *count = 0;
+
+    let (connection_state, service_uuid) = match filter.as_ref() {
+        Some(f) => (f.connection_state, f.service_uuid),
+        None => (0, Uuid::nil()),
+    };
+    if !(0..=2).contains(&connection_state) {
+        set_error_str(&module, "Out of range: connection_state must be 0, 1 or 2");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*module).module;
+    if m.adapter.is_none() || m.runtime.is_none() {
+        error!("null adapter/runtime");
+        set_error_str(&module, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    if in_callback() {
+        set_error_str(&module, IN_CALLBACK);
+        return ERROR_RUNTIME_ERROR;
+    }
+
+    let runtime = m.runtime.as_ref().unwrap();
+    let found = runtime.block_on(async {
+        let mut found = Vec::new();
+        for (p, properties) in known_peripherals(m.adapter.as_ref().unwrap()).await? {
+            let services = advertised_services(properties.as_ref());
+            if !service_uuid.is_nil() && !services.contains(&service_uuid) {
+                continue;
+            }
+            if connection_state != 0 {
+                match p.is_connected().await {
+                    Ok(connected) if connected == (connection_state == 1) => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!(
+                            "Skipping {:?}, connection state unavailable: {:#}",
+                            p.id(),
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+            found.push(acquire(m, p, services, properties));
+        }
+        Ok::<_, Error>(found)
+    });
+
+    match found {
+        Ok(found) => {
+            info!("Found {} peripherals", found.len());
+            let found = found.into_iter().map(PeripheralRef::into_raw).collect();
+            (*peripherals, *count) = into_raw_slice(found);
+            trace!("Success: module_get_peripherals");
+            SUCCESS
+        }
+        Err(e) => {
+            error!("Failed to enumerate peripherals {:?}", e);
+            set_error(&module, &e);
+            error_to_result(&e)
+        }
+    }
+}
+
+/// Releases the list and every handle in it
+#[no_mangle]
+pub unsafe extern "C" fn free_peripheral_list(
+    peripherals: *mut *mut CPeripheral,
+    count: c_int,
+) -> c_int {
+    if peripherals.is_null() {
+        return SUCCESS;
+    }
+
+    for p in free_raw_slice(peripherals, count).iter() {
+        free_peripheral(*p);
+    }
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_get_id(
+    peripheral: *mut CPeripheral,
+    id: *mut *const c_char,
+) -> c_int {
+    if peripheral.is_null() {
+        *id = null();
+        return INVALID_ARGUMENT;
+    }
+
+    let p = &(*peripheral).p;
+    let id_str = CString::new(p.peripheral.id().to_string()).unwrap();
+    *id = id_str.into_raw();
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_get_address(
+    peripheral: *mut CPeripheral,
+    address: *mut u64,
+) -> c_int {
+    if peripheral.is_null() {
+        *address = 0;
+        return INVALID_ARGUMENT;
+    }
+
+    let p = &(*peripheral).p;
+    *address = get_long_addr(p.peripheral.address());
+    SUCCESS
+}
+
+/// Logs a failed peripheral operation and records it as the peripheral's last error, returning
+/// the result code handed to the host
+fn record_error(ap: &PeripheralHandle, operation: &str, e: &Error) -> c_int {
+    error!("Error calling {operation}: {:#}", e);
+    *ap.last_error.lock().unwrap() = error_into_cstring(e);
+    error_to_result(e)
+}
+
+/// When an operation times out. Taken once from its `timeout_ms`, so the steps of an operation
+/// share one budget rather than each getting the whole of it.
+#[derive(Clone, Copy)]
+struct Deadline(Option<(Instant, Duration)>);
+
+impl Deadline {
+    /// Waits indefinitely for 0
+    fn after(timeout_ms: u32) -> Deadline {
+        Deadline((timeout_ms != 0).then(|| {
+            let timeout = Duration::from_millis(timeout_ms.into());
+            (Instant::now() + timeout, timeout)
+        }))
+    }
+}
+
+/// Fails with [`Error::TimedOut`] once the deadline has passed
+async fn with_timeout<T>(
+    deadline: Deadline,
+    operation: impl Future<Output = BleResult<T>>,
+) -> BleResult<T> {
+    let Deadline(Some((at, timeout))) = deadline else {
+        return operation.await;
+    };
+
+    tokio::time::timeout_at(at, operation)
+        .await
+        .unwrap_or(Err(Error::TimedOut(timeout)))
+}
+
+/// Runs a peripheral operation until it completes, times out or is cancelled, recording any
+/// failure. Shared by the callback and `_sync` variants so both report failures the same way.
+async fn run<T>(
+    ap: &PeripheralHandle,
+    name: &str,
+    deadline: Deadline,
+    cancel: Option<&Notify>,
+    operation: impl Future<Output = BleResult<T>>,
+) -> Result<T, c_int> {
+    let cancelled = async {
+        match cancel {
+            Some(cancel) => cancel.notified().await,
+            None => pending().await,
+        }
+    };
+    let result = tokio::select! {
+        result = with_timeout(deadline, operation) => result,
+        _ = cancelled => {
+            info!("Cancelled {name}");
+            *ap.last_error.lock().unwrap() = CString::new("Operation cancelled").unwrap();
+            return Err(ERROR_CANCELLED);
+        }
+    };
+    match result {
+        Ok(v) => Ok(v),
+        Err(e) => Err(record_error(ap, name, &e)),
+    }
+}
+
+/// Runs `connect`, reporting the transitions to the peripheral's connection callback and
+/// restoring the recorded subscriptions when automatic resubscription is on. A connection whose
+/// subscriptions cannot be restored is dropped again, so the operation fails as a whole.
+async fn connect(
+    ap: &PeripheralHandle,
+    deadline: Deadline,
+    cancel: Option<&Notify>,
+) -> Result<(), c_int> {
+    ap.connection_update(
+        Some(ConnectionState::Disconnected),
+        ConnectionState::Connecting,
+        SUCCESS,
+    );
+    let result = match run(ap, "connect", deadline, cancel, ap.peripheral.connect()).await {
+        Ok(()) if ap.connections.auto_resubscribe(&ap.peripheral.id()) => {
+            let restored = resubscribe(ap, deadline, cancel).await;
+            if restored.is_err() {
+                if let Err(e) = ap.peripheral.disconnect().await {
+                    warn!("Failed to disconnect after resubscribing failed: {e}");
+                }
+            }
+            restored
+        }
+        result => result,
+    };
+    match result {
+        Ok(()) => ap.connection_update(None, ConnectionState::Connected, SUCCESS),
+        Err(code) => ap.connection_update(
+            Some(ConnectionState::Connecting),
+            ConnectionState::Disconnected,
+            code,
+        ),
+    }
+    result
+}
+
+/// Runs `disconnect`, reporting the transitions to the peripheral's connection callback
+async fn disconnect(
+    ap: &PeripheralHandle,
+    deadline: Deadline,
+    cancel: Option<&Notify>,
+) -> Result<(), c_int> {
+    ap.connections.cancel_reconnect(&ap.peripheral.id());
+    ap.connection_update(
+        Some(ConnectionState::Connected),
+        ConnectionState::Disconnecting,
+        SUCCESS,
+    );
+    let result = run(
+        ap,
+        "disconnect",
+        deadline,
+        cancel,
+        ap.peripheral.disconnect(),
+    )
+    .await;
+    match result {
+        Ok(()) => {
+            let id = ap.peripheral.id();
+            if !ap.connections.auto_resubscribe(&id) {
+                ap.connections.clear_subscriptions(&id);
+            }
+            ap.connection_update(None, ConnectionState::Disconnected, SUCCESS)
+        }
+        Err(code) => ap.connection_update(
+            Some(ConnectionState::Disconnecting),
+            ConnectionState::Connected,
+            code,
+        ),
+    }
+    result
+}
+
+/// Runs `subscribe`, remembering the characteristic so a reconnection can restore it
+async fn subscribe(
+    ap: &PeripheralHandle,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    deadline: Deadline,
+    cancel: Option<&Notify>,
+) -> Result<(), c_int> {
+    let characteristic = characteristic(service_uuid, uuid);
+    let subscribe = ap.peripheral.subscribe(&characteristic);
+    run(ap, "subscribe", deadline, cancel, subscribe).await?;
+    ap.set_subscribed(service_uuid, uuid, true);
+    Ok(())
+}
+
+/// Runs `unsubscribe`. While disconnected there is nothing to unsubscribe from, so a recorded
+/// characteristic is only dropped from the set restored on connection.
+async fn unsubscribe(
+    ap: &PeripheralHandle,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    deadline: Deadline,
+    cancel: Option<&Notify>,
+) -> Result<(), c_int> {
+    let id = ap.peripheral.id();
+    let is_connected = ap.peripheral.is_connected();
+    let connected = run(ap, "is_connected", deadline, cancel, is_connected).await?;
+    if !connected
+        && ap
+            .connections
+            .subscriptions(&id)
+            .contains(&(service_uuid, uuid))
+    {
+        ap.set_subscribed(service_uuid, uuid, false);
+        ap.remove_handler(service_uuid, uuid).await;
+        return Ok(());
+    }
+    let characteristic = characteristic(service_uuid, uuid);
+    let unsubscribe = ap.peripheral.unsubscribe(&characteristic);
+    run(ap, "unsubscribe", deadline, cancel, unsubscribe).await?;
+    ap.set_subscribed(service_uuid, uuid, false);
+    ap.remove_handler(service_uuid, uuid).await;
+    Ok(())
+}
+
+/// Runs `discover_services`, indexing the characteristics found for notifications
+async fn discover_services(
+    ap: &PeripheralHandle,
+    deadline: Deadline,
+    cancel: Option<&Notify>,
+) -> Result<(), c_int> {
+    let discover = ap.peripheral.discover_services();
+    run(ap, "discover_services", deadline, cancel, discover).await?;
+    debug!("Services discovered");
+    ap.characteristic_services.refresh(&ap.peripheral);
+    Ok(())
+}
+
+/// Discovers services and subscribes again to every recorded characteristic
+async fn resubscribe(
+    ap: &PeripheralHandle,
+    deadline: Deadline,
+    cancel: Option<&Notify>,
+) -> Result<(), c_int> {
+    discover_services(ap, deadline, cancel).await?;
+    for (service_uuid, uuid) in ap.connections.subscriptions(&ap.peripheral.id()) {
+        debug!("Resubscribing to {service_uuid}:{uuid}");
+        let characteristic = characteristic(service_uuid, uuid);
+        let subscribe = ap.peripheral.subscribe(&characteristic);
+        run(ap, "subscribe", deadline, cancel, subscribe).await?;
+    }
+    Ok(())
+}
+
+/// Handle to an operation started by one of the asynchronous peripheral functions, released
+/// with `free_operation`
+pub struct COperation {
+    cancel: Arc<Notify>,
+}
+
+unsafe fn clear_operation(operation: *mut *mut COperation) {
+    if !operation.is_null() {
+        *operation = null_mut();
+    }
+}
+
+/// Hands the host a handle for the operation about to be spawned, if it asked for one
+unsafe fn start_operation(operation: *mut *mut COperation) -> Arc<Notify> {
+    let cancel = Arc::new(Notify::new());
+    if !operation.is_null() {
+        *operation = Box::into_raw(Box::new(COperation {
+            cancel: cancel.clone(),
+        }));
+    }
+    cancel
+}
+
+/// Aborts the operation, whose callback then receives `ERROR_CANCELLED`. Has no effect once the
+/// callback has run.
+#[no_mangle]
+pub unsafe extern "C" fn operation_cancel(operation: *mut COperation) -> c_int {
+    trace!("Enter: operation_cancel");
+    if operation.is_null() {
+        error!("null operation");
+        return INVALID_ARGUMENT;
+    }
+
+    (*operation).cancel.notify_one();
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free_operation(operation: *mut COperation) -> c_int {
+    free_ptr(operation)
+}
+
+fn result_code<T>(result: &Result<T, c_int>) -> c_int {
+    match result {
+        Ok(_) => SUCCESS,
+        Err(code) => *code,
+    }
+}
+
+/// Characteristics are looked up by UUID, so the properties and descriptors are left empty
+fn characteristic(service_uuid: Uuid, uuid: Uuid) -> Characteristic {
+    Characteristic {
+        service_uuid,
+        uuid,
+        descriptors: BTreeSet::default(),
+        properties: CharPropFlags::empty(),
+    }
+}
+
+fn write_type(with_response: bool) -> WriteType {
+    if with_response {
+        WriteType::WithResponse
+    } else {
+        WriteType::WithoutResponse
+    }
+}
+
+type IsConnectedCallback = extern "C" fn(result: c_int, connected: c_int, user_data: *mut c_void);
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_is_connected(
+    peripheral: *mut CPeripheral,
+    timeout_ms: u32,
+    completed_callback: IsConnectedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
+) -> c_int {
+    trace!("Enter: peripheral_is_connected");
+    clear_operation(operation);
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*peripheral).module;
 
     if m.runtime.is_none() {
         error!("null runtime handle");
@@ -488,18 +1620,30 @@ pub unsafe extern "C" fn peripheral_is_connected(
     let runtime = m.runtime.as_ref().unwrap();
 
     let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
     runtime.spawn(async move {
-        match ap.peripheral.is_connected().await {
+        let (result, connected) = match run(
+            &ap,
+            "is_connected",
+            Deadline::after(timeout_ms),
+            Some(&cancel),
+            ap.peripheral.is_connected(),
+        )
+        .await
+        {
             Ok(v) => {
                 debug!("Connected: {v}");
-                completed_callback(SUCCESS, c_int::from(v));
+                (SUCCESS, c_int::from(v))
             }
-            Err(e) => {
-                error!("Error calling is_connected: {:#}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                completed_callback(error_to_result(&e), 0);
-            }
-        }
+            Err(code) => (code, 0),
+        };
+        let event = Event::IsConnected {
+            callback: completed_callback,
+            result,
+            connected,
+        };
+        ap.events.deliver(user_data, event);
     });
 
     trace!("Success: peripheral_is_connected");
@@ -509,9 +1653,13 @@ pub unsafe extern "C" fn peripheral_is_connected(
 #[no_mangle]
 pub unsafe extern "C" fn peripheral_connect(
     peripheral: *mut CPeripheral,
+    timeout_ms: u32,
     completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
 ) -> c_int {
     trace!("Enter: peripheral_connect");
+    clear_operation(operation);
     if peripheral.is_null() {
         error!("null peripheral handle");
         return INVALID_ARGUMENT;
@@ -528,18 +1676,17 @@ pub unsafe extern "C" fn peripheral_connect(
     let runtime = m.runtime.as_ref().unwrap();
 
     let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
     runtime.spawn(async move {
-        match ap.peripheral.connect().await {
-            Ok(()) => {
-                debug!("Connected");
-                completed_callback(SUCCESS);
-            }
-            Err(e) => {
-                error!("Error calling connect: {:#}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                completed_callback(error_to_result(&e));
-            }
+        let result = connect(&ap, Deadline::after(timeout_ms), Some(&cancel)).await;
+        if result.is_ok() {
+            debug!("Connected");
         }
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
     });
     trace!("Success: peripheral_connect");
     SUCCESS
@@ -548,9 +1695,13 @@ pub unsafe extern "C" fn peripheral_connect(
 #[no_mangle]
 pub unsafe extern "C" fn peripheral_disconnect(
     peripheral: *mut CPeripheral,
+    timeout_ms: u32,
     completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
 ) -> c_int {
     trace!("Enter: peripheral_disconnect");
+    clear_operation(operation);
     if peripheral.is_null() {
         error!("null peripheral handle");
         return INVALID_ARGUMENT;
@@ -567,18 +1718,17 @@ pub unsafe extern "C" fn peripheral_disconnect(
     let runtime = m.runtime.as_ref().unwrap();
 
     let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
     runtime.spawn(async move {
-        match ap.peripheral.disconnect().await {
-            Ok(()) => {
-                debug!("Disconnected");
-                completed_callback(SUCCESS);
-            }
-            Err(e) => {
-                error!("Error calling disconnect: {:#}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                completed_callback(error_to_result(&e));
-            }
+        let result = disconnect(&ap, Deadline::after(timeout_ms), Some(&cancel)).await;
+        if result.is_ok() {
+            debug!("Disconnected");
         }
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
     });
     trace!("Success: peripheral_disconnect");
     SUCCESS
@@ -587,9 +1737,13 @@ pub unsafe extern "C" fn peripheral_disconnect(
 #[no_mangle]
 pub unsafe extern "C" fn peripheral_discover_services(
     peripheral: *mut CPeripheral,
+    timeout_ms: u32,
     completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
 ) -> c_int {
     trace!("Enter: peripheral_discover_services");
+    clear_operation(operation);
     if peripheral.is_null() {
         error!("null peripheral handle");
         return INVALID_ARGUMENT;
@@ -606,24 +1760,48 @@ pub unsafe extern "C" fn peripheral_discover_services(
     let runtime = m.runtime.as_ref().unwrap();
 
     let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
     runtime.spawn(async move {
-        match ap.peripheral.discover_services().await {
-            Ok(()) => {
-                debug!("Disconnected");
-                completed_callback(SUCCESS);
-            }
-            Err(e) => {
-                error!("Error calling discover_services: {:#?}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                completed_callback(error_to_result(&e));
-            }
-        }
+        let result = discover_services(&ap, Deadline::after(timeout_ms), Some(&cancel)).await;
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
     });
 
     trace!("Success: peripheral_discover_services");
     SUCCESS
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_get_advertised_services(
+    peripheral: *mut CPeripheral,
+    services: *mut *mut Uuid,
+    service_count: *mut c_int,
+) -> c_int {
+    if peripheral.is_null() || services.is_null() || service_count.is_null() {
+        return INVALID_ARGUMENT;
+    }
+
+    let p = &(*peripheral).p;
+    (*services, *service_count) = into_raw_slice(p.services.lock().unwrap().clone());
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free_advertised_services(
+    services: *mut Uuid,
+    service_count: c_int,
+) -> c_int {
+    if services.is_null() {
+        return SUCCESS;
+    }
+
+    free_raw_slice(services, service_count);
+    SUCCESS
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn peripheral_get_services(
     peripheral: *mut CPeripheral,
@@ -682,6 +1860,99 @@ pub unsafe extern "C" fn peripheral_get_services(
     SUCCESS
 }
 
+/// Returns the peripheral's advertisement properties as of its latest event or lookup. Release
+/// them with `free_peripheral_properties`. It does not block, so callbacks can call it.
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_get_properties(
+    peripheral: *mut CPeripheral,
+    properties: *mut *mut PeripheralPropertiesDescriptor,
+) -> c_int {
+    trace!("Enter: peripheral_get_properties");
+    if peripheral.is_null() || properties.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+    *properties = null_mut();
+
+    let p = &(*peripheral).p;
+    let Some(props) = p.properties.lock().unwrap().clone() else {
+        warn!("No properties available for {:?}", p.peripheral.id());
+        set_peripheral_error_str(&peripheral, "No properties available");
+        return ERROR_DEVICE_NOT_FOUND;
+    };
+
+    let (manufacturer_data, manufacturer_data_count) = into_raw_slice(
+        props
+            .manufacturer_data
+            .into_iter()
+            .map(|(manufacturer_id, data)| {
+                let (data, data_length) = into_raw_slice(data);
+                ManufacturerDataDescriptor {
+                    manufacturer_id,
+                    data,
+                    data_length,
+                }
+            })
+            .collect(),
+    );
+    let (service_data, service_data_count) = into_raw_slice(
+        props
+            .service_data
+            .into_iter()
+            .map(|(uuid, data)| {
+                let (data, data_length) = into_raw_slice(data);
+                ServiceDataDescriptor {
+                    uuid,
+                    data,
+                    data_length,
+                }
+            })
+            .collect(),
+    );
+    let (services, service_count) = into_raw_slice(props.services);
+
+    *properties = Box::into_raw(Box::new(PeripheralPropertiesDescriptor {
+        address: get_long_addr(props.address),
+        address_type: props.address_type.map_or(0, |t| t.num() as c_int),
+        local_name: props
+            .local_name
+            .and_then(|n| CString::new(n).ok())
+            .map_or(null_mut(), CString::into_raw),
+        has_tx_power_level: props.tx_power_level.is_some(),
+        tx_power_level: props.tx_power_level.unwrap_or_default(),
+        has_rssi: props.rssi.is_some(),
+        rssi: props.rssi.unwrap_or_default(),
+        manufacturer_data,
+        manufacturer_data_count,
+        service_data,
+        service_data_count,
+        services,
+        service_count,
+    }));
+    trace!("Success: peripheral_get_properties");
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free_peripheral_properties(
+    properties: *mut PeripheralPropertiesDescriptor,
+) -> c_int {
+    if properties.is_null() {
+        return SUCCESS;
+    }
+
+    let props = Box::from_raw(properties);
+    free_string(props.local_name);
+    for md in free_raw_slice(props.manufacturer_data, props.manufacturer_data_count).iter() {
+        free_raw_slice(md.data, md.data_length);
+    }
+    for sd in free_raw_slice(props.service_data, props.service_data_count).iter() {
+        free_raw_slice(sd.data, sd.data_length);
+    }
+    free_raw_slice(props.services, props.service_count);
+    SUCCESS
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn free_peripheral_services(services: *mut *mut u8) -> c_int {
     let i_raw = services as *mut u32;
@@ -691,19 +1962,280 @@ pub unsafe extern "C" fn free_peripheral_services(services: *mut *mut u8) -> c_i
     SUCCESS
 }
 
-type NotifyCallback = extern "C" fn(uuid: Uuid, data: *const u8, data_length: c_int);
+type NotifyCallback = extern "C" fn(
+    id: u64,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    data: *const u8,
+    data_length: c_int,
+    user_data: *mut c_void,
+);
+
+/// The service of each discovered characteristic, which notifications do not carry. Built when
+/// the handle is created and rebuilt after services are discovered, rather than searched for each
+/// notification.
+#[derive(Clone)]
+struct ServiceIndex(Arc<std::sync::Mutex<HashMap<Uuid, Uuid>>>);
+
+impl ServiceIndex {
+    /// Indexes the services already discovered, possibly through an earlier handle
+    fn new(peripheral: &Peripheral) -> ServiceIndex {
+        let index = ServiceIndex(Arc::default());
+        index.refresh(peripheral);
+        index
+    }
+
+    fn refresh(&self, peripheral: &Peripheral) {
+        let mut services = HashMap::new();
+        for c in peripheral.characteristics() {
+            services
+                .entry(c.uuid)
+                .and_modify(|service: &mut Uuid| {
+                    if *service != c.service_uuid {
+                        *service = Uuid::nil();
+                    }
+                })
+                .or_insert(c.service_uuid);
+        }
+        *self.0.lock().unwrap() = services;
+    }
+
+    /// The service owning the characteristic, or nil if it is unknown or found in several
+    /// services
+    fn service_uuid(&self, uuid: &Uuid) -> Uuid {
+        self.0
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .copied()
+            .unwrap_or_default()
+    }
+}
 
+/// Delivers every notification from the peripheral to `notify_callback`, replacing the listener
+/// from an earlier call on this handle. The listener stops when the handle is released.
 #[no_mangle]
 pub unsafe extern "C" fn peripheral_register_notification_events(
     peripheral: *mut CPeripheral,
     ready: CompletedCallback,
     notify_callback: NotifyCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    trace!("Enter: peripheral_register_notification_events");
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*peripheral).module;
+
+    if m.runtime.is_none() {
+        error!("null runtime handle");
+        set_peripheral_error_str(&peripheral, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    let runtime = m.runtime.as_ref().unwrap();
+    let p = &(*peripheral).p;
+    // The handle owns the listener, so the listener only refers to it weakly
+    let handle = Arc::downgrade(p);
+    let peripheral = p.peripheral.clone();
+    let events = Arc::clone(&p.events);
+    let services = p.characteristic_services.clone();
+    let user_data = UserData(user_data);
+    let listener = runtime.spawn(async move {
+        match peripheral.notifications().await {
+            Ok(mut n) => {
+                debug!("Notifications listening");
+                let event = Event::Completed {
+                    callback: ready,
+                    result: SUCCESS,
+                };
+                events.deliver(user_data, event);
+                let addr = get_long_addr(peripheral.address());
+                while let Some(data) = n.next().await {
+                    info!("Received {} bytes on {}", data.value.len(), data.uuid);
+                    let event = Event::Notification {
+                        callback: notify_callback,
+                        id: addr,
+                        service_uuid: services.service_uuid(&data.uuid),
+                        uuid: data.uuid,
+                        data: data.value,
+                    };
+                    events.deliver(user_data, event);
+                }
+            }
+            Err(e) => {
+                let result = match handle.upgrade() {
+                    Some(ap) => record_error(&ap, "notifications", &e),
+                    None => error_to_result(&e),
+                };
+                events.deliver(
+                    user_data,
+                    Event::Completed {
+                        callback: ready,
+                        result,
+                    },
+                );
+            }
+        }
+    });
+
+    if let Some(previous) = p.listener.lock().unwrap().replace(listener) {
+        debug!("Replacing notification listener");
+        previous.abort();
+    }
+    trace!("Success: peripheral_register_notification_events");
+    SUCCESS
+}
+
+/// Stops the listener started by `peripheral_register_notification_events`. Does nothing if
+/// none is running.
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_unregister_notification_events(
+    peripheral: *mut CPeripheral,
+) -> c_int {
+    trace!("Enter: peripheral_unregister_notification_events");
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let p = &(*peripheral).p;
+    if let Some(listener) = p.listener.lock().unwrap().take() {
+        debug!("Notifications no longer listening");
+        listener.abort();
+    }
+    trace!("Success: peripheral_unregister_notification_events");
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_subscribe(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    timeout_ms: u32,
+    completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
+) -> c_int {
+    trace!("Enter: peripheral_subscribe");
+    clear_operation(operation);
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*peripheral).module;
+
+    if m.runtime.is_none() {
+        error!("null runtime handle");
+        set_peripheral_error_str(&peripheral, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    info!("Subscribing notification for {service_uuid}:{uuid}");
+    let runtime = m.runtime.as_ref().unwrap();
+    let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
+    runtime.spawn(async move {
+        let result = subscribe(
+            &ap,
+            service_uuid,
+            uuid,
+            Deadline::after(timeout_ms),
+            Some(&cancel),
+        )
+        .await;
+        if result.is_ok() {
+            debug!("Notifications subscribed");
+        }
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
+    });
+    trace!("Success: peripheral_subscribe");
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_unsubscribe(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    timeout_ms: u32,
+    completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
+) -> c_int {
+    trace!("Enter: peripheral_unsubscribe");
+    clear_operation(operation);
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*peripheral).module;
+
+    if m.runtime.is_none() {
+        error!("null runtime handle");
+        set_peripheral_error_str(&peripheral, "Invalid module");
+        return INVALID_ARGUMENT;
+    }
+
+    info!("Unsubscribing notification for {service_uuid}:{uuid}");
+    let runtime = m.runtime.as_ref().unwrap();
+    let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
+    runtime.spawn(async move {
+        let result = unsubscribe(
+            &ap,
+            service_uuid,
+            uuid,
+            Deadline::after(timeout_ms),
+            Some(&cancel),
+        )
+        .await;
+        if result.is_ok() {
+            debug!("Notifications Unsubscribed");
+        }
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
+    });
+    trace!("Success: peripheral_unsubscribe");
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_write(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    with_response: bool,
+    data: *mut u8,
+    data_length: u32,
+    timeout_ms: u32,
+    completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
 ) -> c_int {
-    trace!("Enter: peripheral_register_notification_events");
+    trace!("Enter: peripheral_write");
+    clear_operation(operation);
     if peripheral.is_null() {
         error!("null peripheral handle");
         return INVALID_ARGUMENT;
     }
+    if data.is_null() {
+        error!("null data");
+        set_peripheral_error_str(&peripheral, "Null argument: data");
+        return INVALID_ARGUMENT;
+    }
 
     let m = &(*peripheral).module;
 
@@ -713,38 +2245,53 @@ pub unsafe extern "C" fn peripheral_register_notification_events(
         return INVALID_ARGUMENT;
     }
 
+    info!("Writing {data_length} bytes to {service_uuid}:{uuid} (with_response: {with_response})");
     let runtime = m.runtime.as_ref().unwrap();
     let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
+    // The host may release its buffer as soon as this returns
+    let data = from_raw_parts(data, data_length as usize).to_vec();
     runtime.spawn(async move {
-        match ap.peripheral.notifications().await {
-            Ok(mut n) => {
-                debug!("Notifications listening");
-                ready(SUCCESS);
-                while let Some(data) = n.next().await {
-                    info!("Received {} bytes on {}", data.value.len(), data.uuid);
-                    notify_callback(data.uuid, data.value.as_ptr(), data.value.len() as c_int)
-                }
-            }
-            Err(e) => {
-                error!("Error calling connect: {:#}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                ready(error_to_result(&e));
-            }
+        let characteristic = characteristic(service_uuid, uuid);
+        let write = ap
+            .peripheral
+            .write(&characteristic, &data, write_type(with_response));
+        let result = run(
+            &ap,
+            "write",
+            Deadline::after(timeout_ms),
+            Some(&cancel),
+            write,
+        )
+        .await;
+        if result.is_ok() {
+            debug!("Data written");
         }
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
     });
-
-    trace!("Success: peripheral_register_notification_events");
+    trace!("Success: peripheral_write");
     SUCCESS
 }
 
+type ReadCallback =
+    extern "C" fn(result: c_int, data: *const u8, data_length: c_int, user_data: *mut c_void);
+
 #[no_mangle]
-pub unsafe extern "C" fn peripheral_subscribe(
+pub unsafe extern "C" fn peripheral_read(
     peripheral: *mut CPeripheral,
     service_uuid: Uuid,
     uuid: Uuid,
-    completed_callback: CompletedCallback,
+    timeout_ms: u32,
+    completed_callback: ReadCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
 ) -> c_int {
-    trace!("Enter: peripheral_subscribe");
+    trace!("Enter: peripheral_read");
+    clear_operation(operation);
     if peripheral.is_null() {
         error!("null peripheral handle");
         return INVALID_ARGUMENT;
@@ -758,43 +2305,52 @@ pub unsafe extern "C" fn peripheral_subscribe(
         return INVALID_ARGUMENT;
     }
 
-    info!("Subscribing notification for {service_uuid}:{uuid}");
+    info!("Reading from {service_uuid}:{uuid}");
     let runtime = m.runtime.as_ref().unwrap();
     let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
     runtime.spawn(async move {
-        match ap
-            .peripheral
-            .subscribe(&Characteristic {
-                service_uuid,
-                uuid,
-                descriptors: BTreeSet::default(),
-                properties: CharPropFlags::empty(),
-            })
-            .await
+        let characteristic = characteristic(service_uuid, uuid);
+        let (result, data) = match run(
+            &ap,
+            "read",
+            Deadline::after(timeout_ms),
+            Some(&cancel),
+            ap.peripheral.read(&characteristic),
+        )
+        .await
         {
-            Ok(()) => {
-                debug!("Notifications subscribed");
-                completed_callback(SUCCESS)
+            Ok(data) => {
+                debug!("Read {} bytes", data.len());
+                (SUCCESS, data)
             }
-            Err(e) => {
-                error!("Error calling connect: {:#}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                completed_callback(error_to_result(&e));
-            }
-        }
+            Err(code) => (code, Vec::new()),
+        };
+        let event = Event::Read {
+            callback: completed_callback,
+            result,
+            data,
+        };
+        ap.events.deliver(user_data, event);
     });
-    trace!("Success: peripheral_subscribe");
+    trace!("Success: peripheral_read");
     SUCCESS
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn peripheral_unsubscribe(
+pub unsafe extern "C" fn peripheral_read_descriptor(
     peripheral: *mut CPeripheral,
     service_uuid: Uuid,
+    characteristic_uuid: Uuid,
     uuid: Uuid,
-    completed_callback: CompletedCallback,
+    timeout_ms: u32,
+    completed_callback: ReadCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
 ) -> c_int {
-    trace!("Enter: peripheral_unsubscribe");
+    trace!("Enter: peripheral_read_descriptor");
+    clear_operation(operation);
     if peripheral.is_null() {
         error!("null peripheral handle");
         return INVALID_ARGUMENT;
@@ -808,46 +2364,59 @@ pub unsafe extern "C" fn peripheral_unsubscribe(
         return INVALID_ARGUMENT;
     }
 
-    info!("Unsubscribing notification for {service_uuid}:{uuid}");
+    info!("Reading descriptor {service_uuid}:{characteristic_uuid}:{uuid}");
     let runtime = m.runtime.as_ref().unwrap();
     let ap = (*peripheral).p.clone();
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
     runtime.spawn(async move {
-        match ap
-            .peripheral
-            .unsubscribe(&Characteristic {
-                service_uuid,
-                uuid,
-                descriptors: BTreeSet::default(),
-                properties: CharPropFlags::empty(),
-            })
-            .await
+        let descriptor = Descriptor {
+            uuid,
+            service_uuid,
+            characteristic_uuid,
+        };
+        let read = ap.peripheral.read_descriptor(&descriptor);
+        let (result, data) = match run(
+            &ap,
+            "read_descriptor",
+            Deadline::after(timeout_ms),
+            Some(&cancel),
+            read,
+        )
+        .await
         {
-            Ok(()) => {
-                debug!("Notifications Unsubscribed");
-                completed_callback(SUCCESS)
+            Ok(data) => {
+                debug!("Read {} bytes from descriptor", data.len());
+                (SUCCESS, data)
             }
-            Err(e) => {
-                error!("Error calling connect: {:#}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                completed_callback(error_to_result(&e));
-            }
-        }
+            Err(code) => (code, Vec::new()),
+        };
+        let event = Event::Read {
+            callback: completed_callback,
+            result,
+            data,
+        };
+        ap.events.deliver(user_data, event);
     });
-    trace!("Success: peripheral_unsubscribe");
+    trace!("Success: peripheral_read_descriptor");
     SUCCESS
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn peripheral_write(
+pub unsafe extern "C" fn peripheral_write_descriptor(
     peripheral: *mut CPeripheral,
     service_uuid: Uuid,
+    characteristic_uuid: Uuid,
     uuid: Uuid,
-    with_response: bool,
     data: *mut u8,
     data_length: u32,
+    timeout_ms: u32,
     completed_callback: CompletedCallback,
+    user_data: *mut c_void,
+    operation: *mut *mut COperation,
 ) -> c_int {
-    trace!("Enter: peripheral_write");
+    trace!("Enter: peripheral_write_descriptor");
+    clear_operation(operation);
     if peripheral.is_null() {
         error!("null peripheral handle");
         return INVALID_ARGUMENT;
@@ -866,39 +2435,37 @@ pub unsafe extern "C" fn peripheral_write(
         return INVALID_ARGUMENT;
     }
 
-    info!("Writing {data_length} bytes to {service_uuid}:{uuid} (with_response: {with_response})");
+    info!("Writing {data_length} bytes to descriptor {service_uuid}:{characteristic_uuid}:{uuid}");
     let runtime = m.runtime.as_ref().unwrap();
     let ap = (*peripheral).p.clone();
-    let data_arr = from_raw_parts(data, data_length as usize);
+    let user_data = UserData(user_data);
+    let cancel = start_operation(operation);
+    // The host may release its buffer as soon as this returns
+    let data = from_raw_parts(data, data_length as usize).to_vec();
     runtime.spawn(async move {
-        let characteristic = Characteristic {
-            service_uuid,
+        let descriptor = Descriptor {
             uuid,
-            descriptors: BTreeSet::default(),
-            properties: CharPropFlags::empty(),
-        };
-        let write_type = if with_response {
-            WriteType::WithResponse
-        } else {
-            WriteType::WithoutResponse
+            service_uuid,
+            characteristic_uuid,
         };
-        match ap
-            .peripheral
-            .write(&characteristic, data_arr, write_type)
-            .await
-        {
-            Ok(()) => {
-                debug!("Data written");
-                completed_callback(SUCCESS)
-            }
-            Err(e) => {
-                error!("Error calling write: {:#}", e);
-                *ap.last_error.lock().await = error_into_cstring(&e);
-                completed_callback(error_to_result(&e));
-            }
+        let write = ap.peripheral.write_descriptor(&descriptor, &data);
+        let result = run(
+            &ap,
+            "write_descriptor",
+            Deadline::after(timeout_ms),
+            Some(&cancel),
+            write,
+        )
+        .await;
+        if result.is_ok() {
+            debug!("Descriptor written");
         }
+        let result = result_code(&result);
+        let callback = completed_callback;
+        ap.events
+            .deliver(user_data, Event::Completed { callback, result });
     });
-    trace!("Success: peripheral_write");
+    trace!("Success: peripheral_write_descriptor");
     SUCCESS
 }
 
@@ -908,7 +2475,8 @@ pub unsafe extern "C" fn get_last_module_error(module: *mut CModule) -> *const c
         return null();
     }
 
-    unsafe { (*module).module.last_error.blocking_lock().as_ptr() }
+    let m = &(*module).module;
+    m.last_error.lock().unwrap().as_ptr()
 }
 
 #[no_mangle]
@@ -917,7 +2485,17 @@ pub unsafe extern "C" fn peripheral_get_last_error(peripheral: *mut CPeripheral)
         return null();
     }
 
-    unsafe { (*peripheral).p.last_error.blocking_lock().as_ptr() }
+    let p = &(*peripheral).p;
+    p.last_error.lock().unwrap().as_ptr()
+}
+
+fn into_raw_slice<T>(v: Vec<T>) -> (*mut T, c_int) {
+    let len = v.len() as c_int;
+    (Box::into_raw(v.into_boxed_slice()) as *mut T, len)
+}
+
+unsafe fn free_raw_slice<T>(ptr: *mut T, len: c_int) -> Box<[T]> {
+    Box::from_raw(slice_from_raw_parts_mut(ptr, len as usize))
 }
 
 unsafe fn free_ptr<T>(handle: *mut T) -> c_int {
@@ -933,9 +2511,15 @@ pub unsafe extern "C" fn free_module(module: *mut CModule) -> c_int {
     free_ptr(module)
 }
 
+/// Releases one reference to the peripheral's handle. Each function returning a peripheral,
+/// including the `found` callback, hands out a reference to the same handle for a device, which
+/// is freed once every reference is released.
 #[no_mangle]
 pub unsafe extern "C" fn free_peripheral(peripheral: *mut CPeripheral) -> c_int {
-    free_ptr(peripheral)
+    if !peripheral.is_null() {
+        release(peripheral);
+    }
+    SUCCESS
 }
 
 #[no_mangle]
@@ -950,6 +2534,866 @@ pub unsafe extern "C" fn free_string(s: *mut c_char) -> c_int {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::mock::tests::{
+        leak, listen, on_completed, on_read, once, wait, Fixture, ADDRESS, CHARACTERISTIC, SERVICE,
+    };
+    use crate::mock::{
+        create_module_mock, mock_adapter_set_state, mock_add_peripheral,
+        mock_peripheral_add_characteristic, mock_peripheral_add_descriptor,
+        mock_peripheral_advertise_services, mock_peripheral_notify,
+        mock_peripheral_set_descriptor_value, mock_peripheral_set_latency,
+        mock_peripheral_set_manufacturer_data, mock_peripheral_set_rssi,
+        mock_peripheral_set_service_data, mock_peripheral_set_tx_power_level, mock_set_adapters,
+    };
+    use crate::sync::{
+        peripheral_connect_sync, peripheral_discover_services_sync, peripheral_subscribe_sync,
+    };
+    use std::sync::mpsc::{channel, Sender};
+
     #[test]
     fn it_works() {}
+
+    const DESCRIPTOR: Uuid = Uuid::from_u128(0x2902);
+
+    unsafe fn read_descriptor(peripheral: *mut CPeripheral, uuid: Uuid) -> (c_int, Vec<u8>) {
+        let (tx, rx) = channel();
+        assert_eq!(
+            SUCCESS,
+            peripheral_read_descriptor(
+                peripheral,
+                SERVICE,
+                CHARACTERISTIC,
+                uuid,
+                0,
+                on_read,
+                once(&tx),
+                null_mut()
+            )
+        );
+        wait(&rx)
+    }
+
+    unsafe fn write_descriptor(peripheral: *mut CPeripheral, uuid: Uuid, value: &[u8]) -> c_int {
+        let mut data = value.to_vec();
+        let (tx, rx) = channel();
+        assert_eq!(
+            SUCCESS,
+            peripheral_write_descriptor(
+                peripheral,
+                SERVICE,
+                CHARACTERISTIC,
+                uuid,
+                data.as_mut_ptr(),
+                data.len() as u32,
+                0,
+                on_completed,
+                once(&tx),
+                null_mut()
+            )
+        );
+        // The operation owns a copy, so the buffer can go before it completes
+        drop(data);
+        wait(&rx)
+    }
+
+    #[test]
+    fn descriptor_values_round_trip() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_add_descriptor(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    DESCRIPTOR
+                )
+            );
+            let value = [1u8, 0];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_descriptor_value(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    DESCRIPTOR,
+                    value.as_ptr(),
+                    value.len() as u32
+                )
+            );
+            f.connect();
+
+            assert_eq!(
+                (SUCCESS, vec![1, 0]),
+                read_descriptor(f.peripheral, DESCRIPTOR)
+            );
+            assert_eq!(SUCCESS, write_descriptor(f.peripheral, DESCRIPTOR, &[2, 0]));
+            assert_eq!(
+                (SUCCESS, vec![2, 0]),
+                read_descriptor(f.peripheral, DESCRIPTOR)
+            );
+
+            let unknown = Uuid::from_u128(0x2903);
+            let (result, value) = read_descriptor(f.peripheral, unknown);
+            assert_eq!(ERROR_NO_SUCH_CHARACTERISTIC, result);
+            assert!(value.is_empty());
+            assert_eq!(
+                ERROR_NO_SUCH_CHARACTERISTIC,
+                write_descriptor(f.peripheral, unknown, &[1])
+            );
+            assert!(!CStr::from_ptr(peripheral_get_last_error(f.peripheral)).is_empty());
+        }
+    }
+
+    /// Name, RSSI, tx power and manufacturer data read through `peripheral_get_properties`
+    type Advertisement = (String, Option<i16>, Option<i16>, Vec<(u16, Vec<u8>)>);
+
+    unsafe fn read_properties(peripheral: *mut CPeripheral) -> Result<Advertisement, c_int> {
+        let mut properties = null_mut();
+        let result = peripheral_get_properties(peripheral, &mut properties);
+        if result != SUCCESS {
+            return Err(result);
+        }
+        let props = &*properties;
+        let name = CStr::from_ptr(props.local_name)
+            .to_string_lossy()
+            .into_owned();
+        let manufacturer_data = from_raw_parts(
+            props.manufacturer_data,
+            props.manufacturer_data_count as usize,
+        )
+        .iter()
+        .map(|md| {
+            let data = from_raw_parts(md.data, md.data_length as usize);
+            (md.manufacturer_id, data.to_vec())
+        })
+        .collect();
+        let advertisement = (
+            name,
+            props.has_rssi.then_some(props.rssi),
+            props.has_tx_power_level.then_some(props.tx_power_level),
+            manufacturer_data,
+        );
+        free_peripheral_properties(properties);
+        Ok(advertisement)
+    }
+
+    extern "C" fn send_properties(
+        _id: u64,
+        peripheral: *mut CPeripheral,
+        _services: *const Uuid,
+        _service_count: c_int,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let found = unsafe { &*(user_data as *const Sender<Result<Advertisement, c_int>>) };
+        let _ = found.send(unsafe { read_properties(peripheral) });
+        0
+    }
+
+    extern "C" fn on_disconnected(_id: u64, _user_data: *mut c_void) {}
+
+    #[test]
+    fn properties_are_read_from_callbacks() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            let data = [1u8, 2, 3];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_manufacturer_data(
+                    module,
+                    ADDRESS,
+                    0x004c,
+                    data.as_ptr(),
+                    data.len() as u32
+                )
+            );
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_tx_power_level(module, ADDRESS, -8)
+            );
+
+            let (tx, rx) = channel::<Result<Advertisement, c_int>>();
+            let found = leak(tx) as *const Sender<_> as *mut c_void;
+            assert_eq!(
+                SUCCESS,
+                set_event_callbacks(module, send_properties, on_disconnected, found)
+            );
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+            let expected = (
+                "Sensor".to_string(),
+                Some(-40),
+                Some(-8),
+                vec![(0x004c, vec![1, 2, 3])],
+            );
+            assert_eq!(Ok(expected.clone()), wait(&rx));
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+
+            let mut peripheral = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            assert_eq!(Ok(expected), read_properties(peripheral));
+            free_peripheral(peripheral);
+            free_module(module);
+        }
+    }
+
+    /// An event forwarded to the `set_event_callbacks_ex` callbacks
+    #[derive(Debug, PartialEq)]
+    enum Forwarded {
+        Connected(u64),
+        Updated(u64),
+        ManufacturerData(u64, u16, Vec<u8>),
+        ServiceData(u64, Uuid, Vec<u8>),
+    }
+
+    unsafe fn forward(user_data: *mut c_void, event: Forwarded) {
+        let _ = (*(user_data as *const Sender<Forwarded>)).send(event);
+    }
+
+    extern "C" fn on_connected(id: u64, user_data: *mut c_void) {
+        unsafe { forward(user_data, Forwarded::Connected(id)) };
+    }
+
+    extern "C" fn on_updated(id: u64, user_data: *mut c_void) {
+        unsafe { forward(user_data, Forwarded::Updated(id)) };
+    }
+
+    extern "C" fn on_manufacturer_data(
+        id: u64,
+        manufacturer_id: u16,
+        data: *const u8,
+        data_length: c_int,
+        user_data: *mut c_void,
+    ) {
+        let data = unsafe { from_raw_parts(data, data_length as usize) }.to_vec();
+        let event = Forwarded::ManufacturerData(id, manufacturer_id, data);
+        unsafe { forward(user_data, event) };
+    }
+
+    extern "C" fn on_service_data(
+        id: u64,
+        service_uuid: Uuid,
+        data: *const u8,
+        data_length: c_int,
+        user_data: *mut c_void,
+    ) {
+        let data = unsafe { from_raw_parts(data, data_length as usize) }.to_vec();
+        unsafe { forward(user_data, Forwarded::ServiceData(id, service_uuid, data)) };
+    }
+
+    #[test]
+    fn advertisement_and_connection_events_are_forwarded() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            for address in [ADDRESS, ADDRESS + 1] {
+                assert_eq!(
+                    SUCCESS,
+                    mock_add_peripheral(module, address, c"Sensor".as_ptr(), -40)
+                );
+            }
+            let (tx, rx) = channel();
+            let callbacks = EventCallbacks {
+                found: None,
+                disconnected: None,
+                connected: Some(on_connected),
+                updated: Some(on_updated),
+                manufacturer_data: Some(on_manufacturer_data),
+                service_data: Some(on_service_data),
+                state_update: None,
+                user_data: leak(tx) as *const Sender<Forwarded> as *mut c_void,
+            };
+            assert_eq!(SUCCESS, set_event_callbacks_ex(module, &callbacks));
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+
+            let data = [1u8, 2];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_manufacturer_data(
+                    module,
+                    ADDRESS,
+                    0x004c,
+                    data.as_ptr(),
+                    data.len() as u32
+                )
+            );
+            assert_eq!(
+                Forwarded::ManufacturerData(ADDRESS, 0x004c, vec![1, 2]),
+                wait(&rx)
+            );
+            let data = [3u8];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_service_data(
+                    module,
+                    ADDRESS + 1,
+                    SERVICE,
+                    data.as_ptr(),
+                    data.len() as u32
+                )
+            );
+            assert_eq!(
+                Forwarded::ServiceData(ADDRESS + 1, SERVICE, vec![3]),
+                wait(&rx)
+            );
+            assert_eq!(SUCCESS, mock_peripheral_set_rssi(module, ADDRESS, -50));
+            assert_eq!(Forwarded::Updated(ADDRESS), wait(&rx));
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+
+            let mut peripheral = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS + 1, &mut peripheral)
+            );
+            assert_eq!(SUCCESS, peripheral_connect_sync(peripheral, 0));
+            assert_eq!(Forwarded::Connected(ADDRESS + 1), wait(&rx));
+            free_peripheral(peripheral);
+            free_module(module);
+        }
+    }
+
+    #[test]
+    fn operations_time_out() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_latency(f.module, ADDRESS, 10_000)
+            );
+            let (tx, rx) = channel();
+            assert_eq!(
+                SUCCESS,
+                peripheral_connect(f.peripheral, 10, on_completed, once(&tx), null_mut())
+            );
+            assert_eq!(ERROR_TIMED_OUT, wait(&rx));
+        }
+    }
+
+    #[test]
+    fn operations_can_be_cancelled() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_latency(f.module, ADDRESS, 10_000)
+            );
+            let (tx, rx) = channel();
+            let mut operation = null_mut();
+            assert_eq!(
+                SUCCESS,
+                peripheral_connect(f.peripheral, 0, on_completed, once(&tx), &mut operation)
+            );
+            assert_eq!(SUCCESS, operation_cancel(operation));
+            assert_eq!(ERROR_CANCELLED, wait(&rx));
+            let error = CStr::from_ptr(peripheral_get_last_error(f.peripheral));
+            assert_eq!(c"Operation cancelled", error);
+            free_operation(operation);
+        }
+    }
+
+    #[test]
+    fn cancelling_a_completed_operation_has_no_effect() {
+        unsafe {
+            let f = Fixture::new();
+            let (tx, rx) = channel();
+            let mut operation = null_mut();
+            assert_eq!(
+                SUCCESS,
+                peripheral_connect(f.peripheral, 0, on_completed, once(&tx), &mut operation)
+            );
+            assert_eq!(SUCCESS, wait(&rx));
+            assert_eq!(SUCCESS, operation_cancel(operation));
+            assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+            free_operation(operation);
+        }
+    }
+
+    #[test]
+    fn notifications_identify_their_source() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            let (tx, rx) = channel();
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    0,
+                    on_completed,
+                    once(&tx),
+                    null_mut()
+                )
+            );
+            assert_eq!(SUCCESS, wait(&rx));
+            let values = listen(f.peripheral);
+
+            let data = [42u8];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_notify(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    data.as_ptr(),
+                    1
+                )
+            );
+            assert_eq!((ADDRESS, SERVICE, CHARACTERISTIC, vec![42]), wait(&values));
+        }
+    }
+
+    #[test]
+    fn notification_listener_is_replaced_and_removed() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            let replaced = listen(f.peripheral);
+            let current = listen(f.peripheral);
+            let notify = |value: u8| {
+                let data = [value];
+                assert_eq!(
+                    SUCCESS,
+                    mock_peripheral_notify(
+                        f.module,
+                        ADDRESS,
+                        SERVICE,
+                        CHARACTERISTIC,
+                        data.as_ptr(),
+                        1
+                    )
+                );
+            };
+
+            notify(1);
+            assert_eq!(vec![1], wait(&current).3);
+            assert!(replaced.recv_timeout(Duration::from_millis(50)).is_err());
+
+            assert_eq!(
+                SUCCESS,
+                peripheral_unregister_notification_events(f.peripheral)
+            );
+            notify(2);
+            assert!(current.recv_timeout(Duration::from_millis(50)).is_err());
+        }
+    }
+
+    #[test]
+    fn notifications_name_the_service_of_their_characteristic() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            let values = listen(f.peripheral);
+            let data = [1u8];
+            let notify = || {
+                assert_eq!(
+                    SUCCESS,
+                    mock_peripheral_notify(
+                        f.module,
+                        ADDRESS,
+                        SERVICE,
+                        CHARACTERISTIC,
+                        data.as_ptr(),
+                        1
+                    )
+                );
+            };
+            notify();
+            assert_eq!(SERVICE, wait(&values).1);
+
+            // Once another service has the same characteristic, its service is unknown
+            let other = Uuid::from_u128(0x2000);
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_add_characteristic(
+                    f.module,
+                    ADDRESS,
+                    other,
+                    CHARACTERISTIC,
+                    CharPropFlags::NOTIFY.bits()
+                )
+            );
+            assert_eq!(SUCCESS, peripheral_discover_services_sync(f.peripheral, 0));
+            notify();
+            assert_eq!(Uuid::nil(), wait(&values).1);
+        }
+    }
+
+    #[test]
+    fn notifications_name_services_discovered_through_an_earlier_handle() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_add_characteristic(
+                    module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    CharPropFlags::NOTIFY.bits()
+                )
+            );
+            let mut peripheral = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            assert_eq!(SUCCESS, peripheral_connect_sync(peripheral, 0));
+            assert_eq!(SUCCESS, peripheral_discover_services_sync(peripheral, 0));
+            free_peripheral(peripheral);
+
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            let values = listen(peripheral);
+            let data = [1u8];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_notify(module, ADDRESS, SERVICE, CHARACTERISTIC, data.as_ptr(), 1)
+            );
+            assert_eq!(SERVICE, wait(&values).1);
+            free_peripheral(peripheral);
+            free_module(module);
+        }
+    }
+
+    #[test]
+    fn notification_listener_stops_with_its_handle() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_add_characteristic(
+                    module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    CharPropFlags::NOTIFY.bits()
+                )
+            );
+            let mut peripheral = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            assert_eq!(SUCCESS, peripheral_connect_sync(peripheral, 0));
+            assert_eq!(SUCCESS, peripheral_discover_services_sync(peripheral, 0));
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            let values = listen(peripheral);
+
+            free_peripheral(peripheral);
+            let data = [1u8];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_notify(module, ADDRESS, SERVICE, CHARACTERISTIC, data.as_ptr(), 1)
+            );
+            assert!(values.recv_timeout(Duration::from_millis(50)).is_err());
+            free_module(module);
+        }
+    }
+
+    #[test]
+    fn known_peripheral_opens_without_scanning() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+
+            let mut peripheral = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            let mut address = 0;
+            assert_eq!(SUCCESS, peripheral_get_address(peripheral, &mut address));
+            assert_eq!(ADDRESS, address);
+
+            let mut id = null();
+            assert_eq!(SUCCESS, peripheral_get_id(peripheral, &mut id));
+            let mut by_id = null_mut();
+            assert_eq!(SUCCESS, module_get_peripheral_by_id(module, id, &mut by_id));
+            assert!(!by_id.is_null());
+            free_string(id as *mut c_char);
+            free_peripheral(by_id);
+            free_peripheral(peripheral);
+
+            assert_eq!(
+                ERROR_DEVICE_NOT_FOUND,
+                module_get_peripheral_by_address(module, ADDRESS + 1, &mut peripheral)
+            );
+            assert!(peripheral.is_null());
+            free_module(module);
+        }
+    }
+
+    unsafe fn list_addresses(module: *mut CModule, filter: *const PeripheralFilter) -> Vec<u64> {
+        let mut peripherals = null_mut();
+        let mut count = 0;
+        assert_eq!(
+            SUCCESS,
+            module_get_peripherals(module, filter, &mut peripherals, &mut count)
+        );
+        let mut addresses: Vec<u64> = from_raw_parts(peripherals, count as usize)
+            .iter()
+            .map(|p| {
+                let mut address = 0;
+                peripheral_get_address(*p, &mut address);
+                address
+            })
+            .collect();
+        free_peripheral_list(peripherals, count);
+        addresses.sort();
+        addresses
+    }
+
+    #[test]
+    fn known_peripherals_can_be_filtered() {
+        unsafe {
+            let f = Fixture::new();
+            let other = ADDRESS + 1;
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(f.module, other, c"Beacon".as_ptr(), -70)
+            );
+            let service = Uuid::from_u128(0x2000);
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_advertise_services(f.module, other, &service, 1)
+            );
+            f.connect();
+
+            assert_eq!(vec![ADDRESS, other], list_addresses(f.module, null()));
+            let connected = PeripheralFilter {
+                connection_state: 1,
+                service_uuid: Uuid::nil(),
+            };
+            assert_eq!(vec![ADDRESS], list_addresses(f.module, &connected));
+            let advertising = PeripheralFilter {
+                connection_state: 2,
+                service_uuid: service,
+            };
+            assert_eq!(vec![other], list_addresses(f.module, &advertising));
+        }
+    }
+
+    unsafe fn advertised_services(peripheral: *mut CPeripheral) -> Vec<Uuid> {
+        let mut services = null_mut();
+        let mut count = 0;
+        assert_eq!(
+            SUCCESS,
+            peripheral_get_advertised_services(peripheral, &mut services, &mut count)
+        );
+        let advertised = from_raw_parts(services, count as usize).to_vec();
+        free_advertised_services(services, count);
+        advertised
+    }
+
+    unsafe fn advertise(module: *mut CModule, services: &[Uuid]) {
+        assert_eq!(
+            SUCCESS,
+            mock_peripheral_advertise_services(
+                module,
+                ADDRESS,
+                services.as_ptr(),
+                services.len() as c_int
+            )
+        );
+    }
+
+    extern "C" fn send_services(
+        _id: u64,
+        _peripheral: *mut CPeripheral,
+        services: *const Uuid,
+        service_count: c_int,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let found = unsafe { &*(user_data as *const Sender<Vec<Uuid>>) };
+        let services = unsafe { from_raw_parts(services, service_count as usize) };
+        let _ = found.send(services.to_vec());
+        0
+    }
+
+    #[test]
+    fn advertised_services_accumulate() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            let [first, second, third] = [0x2000, 0x2001, 0x2002].map(Uuid::from_u128);
+
+            // Each lookup adds the services in the latest advertisement
+            advertise(module, &[first]);
+            let mut peripheral = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut peripheral)
+            );
+            assert_eq!(vec![first], advertised_services(peripheral));
+            advertise(module, &[second]);
+            let mut again = null_mut();
+            assert_eq!(
+                SUCCESS,
+                module_get_peripheral_by_address(module, ADDRESS, &mut again)
+            );
+            assert_eq!(peripheral, again);
+            free_peripheral(again);
+            assert_eq!(vec![first, second], advertised_services(peripheral));
+
+            // So does each advertisement seen while scanning
+            let (tx, rx) = channel();
+            let found = leak(tx) as *const Sender<Vec<Uuid>> as *mut c_void;
+            assert_eq!(
+                SUCCESS,
+                set_event_callbacks(module, send_services, on_disconnected, found)
+            );
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+            advertise(module, &[third]);
+            while wait(&rx) != vec![second, third] {}
+            assert_eq!(vec![first, second, third], advertised_services(peripheral));
+            free_peripheral(peripheral);
+            free_module(module);
+        }
+    }
+
+    extern "C" fn on_state_update(state: c_int, user_data: *mut c_void) {
+        let states = unsafe { &*(user_data as *const Sender<c_int>) };
+        let _ = states.send(state);
+    }
+
+    #[test]
+    fn adapter_state_changes_are_reported() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            let mut info = null_mut();
+            assert_eq!(SUCCESS, module_get_adapter_info(module, &mut info));
+            assert_eq!(c"Mock adapter", CStr::from_ptr(info));
+            free_string(info);
+
+            let (tx, rx) = channel();
+            let callbacks = EventCallbacks {
+                found: None,
+                disconnected: None,
+                connected: None,
+                updated: None,
+                manufacturer_data: None,
+                service_data: None,
+                state_update: Some(on_state_update),
+                user_data: leak(tx) as *const Sender<c_int> as *mut c_void,
+            };
+            assert_eq!(SUCCESS, set_event_callbacks_ex(module, &callbacks));
+
+            let mut state = 0;
+            assert_eq!(SUCCESS, module_get_adapter_state(module, &mut state));
+            assert_eq!(1, state);
+            assert_eq!(SUCCESS, mock_adapter_set_state(module, 2));
+            assert_eq!(2, wait(&rx));
+            assert_eq!(SUCCESS, module_get_adapter_state(module, &mut state));
+            assert_eq!(2, state);
+            free_module(module);
+        }
+    }
+
+    unsafe fn adapter_info(module: *mut CModule) -> String {
+        let mut info = null_mut();
+        assert_eq!(SUCCESS, module_get_adapter_info(module, &mut info));
+        let name = CStr::from_ptr(info).to_str().unwrap().to_string();
+        free_string(info);
+        name
+    }
+
+    #[test]
+    fn adapters_are_listed_and_chosen_by_exact_name() {
+        unsafe {
+            let names = [c"hci10".as_ptr(), c"hci1".as_ptr()];
+            assert_eq!(SUCCESS, mock_set_adapters(names.as_ptr(), 2));
+
+            let (mut adapters, mut count) = (null_mut(), 0);
+            assert_eq!(SUCCESS, list_adapters(&mut adapters, &mut count));
+            let listed: Vec<_> = from_raw_parts(adapters, count as usize)
+                .iter()
+                .map(|a| CStr::from_ptr(*a).to_owned())
+                .collect();
+            assert_eq!(vec![c"hci10".to_owned(), c"hci1".to_owned()], listed);
+            free_adapter_list(adapters, count);
+
+            let mut module = null_mut();
+            assert_eq!(
+                SUCCESS,
+                create_module_with_adapter(&mut module, -1, c"hci1".as_ptr())
+            );
+            assert_eq!("hci1", adapter_info(module));
+            free_module(module);
+
+            assert_eq!(SUCCESS, create_module_with_adapter(&mut module, 0, null()));
+            assert_eq!("hci10", adapter_info(module));
+            free_module(module);
+
+            assert_eq!(
+                ERROR_RUNTIME_ERROR,
+                create_module_with_adapter(&mut module, -1, c"hci".as_ptr())
+            );
+            free_module(module);
+            assert_eq!(
+                ERROR_RUNTIME_ERROR,
+                create_module_with_adapter(&mut module, 2, null())
+            );
+            free_module(module);
+            assert_eq!(SUCCESS, mock_set_adapters(null(), 0));
+        }
+    }
+
+    #[test]
+    fn header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/btleplug_c.h"));
+        let committed = include_str!("../include/btleplug_c.h");
+        assert!(
+            generated == committed,
+            "include/btleplug_c.h is out of date, rebuild with BTLEPLUG_C_UPDATE_HEADER=1"
+        );
+    }
 }
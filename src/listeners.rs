@@ -0,0 +1,198 @@
+//! Adapter event listeners. The module reads the adapter's events once, from a task started with
+//! the module, and hands each event to the callbacks of every listener.
+
+use crate::{set_error_str, CModule, EventCallbacks, INVALID_ARGUMENT, SUCCESS};
+use log::{debug, error, trace};
+use std::collections::BTreeMap;
+use std::ffi::c_int;
+
+#[derive(Default)]
+pub(crate) struct Listeners {
+    next_id: u64,
+    callbacks: BTreeMap<u64, EventCallbacks>,
+    /// The listener set through `set_event_callbacks`, which the next call replaces
+    primary: Option<u64>,
+}
+
+impl Listeners {
+    pub(crate) fn callbacks(&self) -> Vec<EventCallbacks> {
+        self.callbacks.values().copied().collect()
+    }
+
+    fn add(&mut self, callbacks: EventCallbacks) -> u64 {
+        self.next_id += 1;
+        self.callbacks.insert(self.next_id, callbacks);
+        self.next_id
+    }
+
+    pub(crate) fn replace_primary(&mut self, callbacks: EventCallbacks) -> u64 {
+        if let Some(previous) = self.primary.take() {
+            debug!("Replacing event callbacks");
+            self.callbacks.remove(&previous);
+        }
+        let id = self.add(callbacks);
+        self.primary = Some(id);
+        id
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        if self.primary == Some(id) {
+            self.primary = None;
+        }
+        self.callbacks.remove(&id).is_some()
+    }
+}
+
+/// Registers the callbacks with `register`
+pub(crate) unsafe fn add_listener(
+    module: *mut CModule,
+    callbacks: *const EventCallbacks,
+    register: impl FnOnce(&mut Listeners, EventCallbacks) -> u64,
+) -> Result<u64, c_int> {
+    if module.is_null() {
+        error!("null module");
+        return Err(INVALID_ARGUMENT);
+    }
+    if callbacks.is_null() {
+        error!("null callbacks");
+        set_error_str(&module, "Null argument: callbacks");
+        return Err(INVALID_ARGUMENT);
+    }
+
+    let m = &(*module).module;
+    if m.adapter.is_none() || m.runtime.is_none() {
+        error!("null adapter/runtime");
+        set_error_str(&module, "Invalid module");
+        return Err(INVALID_ARGUMENT);
+    }
+
+    let mut listeners = m.listeners.lock().unwrap();
+    Ok(register(&mut listeners, *callbacks))
+}
+
+/// Adds a listener for the adapter's events alongside any others, returning its id in
+/// `listener_id` for `module_remove_event_listener`
+#[no_mangle]
+pub unsafe extern "C" fn module_add_event_listener(
+    module: *mut CModule,
+    callbacks: *const EventCallbacks,
+    listener_id: *mut u64,
+) -> c_int {
+    trace!("Enter: module_add_event_listener");
+    if listener_id.is_null() {
+        error!("null listener id");
+        return INVALID_ARGUMENT;
+    }
+
+    match add_listener(module, callbacks, Listeners::add) {
+        Ok(id) => {
+            *listener_id = id;
+            trace!("Success: module_add_event_listener");
+            SUCCESS
+        }
+        Err(code) => code,
+    }
+}
+
+/// Stops delivering events to the listener. Events already handed to it may still arrive.
+#[no_mangle]
+pub unsafe extern "C" fn module_remove_event_listener(
+    module: *mut CModule,
+    listener_id: u64,
+) -> c_int {
+    trace!("Enter: module_remove_event_listener");
+    if module.is_null() {
+        error!("null module");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*module).module;
+    if !m.listeners.lock().unwrap().remove(listener_id) {
+        error!("Unknown listener {listener_id}");
+        set_error_str(&module, "Unknown listener");
+        return INVALID_ARGUMENT;
+    }
+    trace!("Success: module_remove_event_listener");
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::tests::{leak, send_found, wait, ADDRESS};
+    use crate::mock::{create_module_mock, mock_add_peripheral};
+    use crate::{
+        free_module, set_event_callbacks_ex, start_scan_peripherals, stop_scan_peripherals,
+    };
+    use std::ffi::c_void;
+    use std::ptr::null_mut;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::Duration;
+
+    /// Callbacks reporting found peripherals
+    fn found_callbacks() -> (EventCallbacks, Receiver<u64>) {
+        let (tx, rx) = channel();
+        let callbacks = EventCallbacks {
+            found: Some(send_found),
+            disconnected: None,
+            connected: None,
+            updated: None,
+            manufacturer_data: None,
+            service_data: None,
+            state_update: None,
+            user_data: leak(tx) as *const Sender<u64> as *mut c_void,
+        };
+        (callbacks, rx)
+    }
+
+    unsafe fn add(module: *mut CModule) -> (u64, Receiver<u64>) {
+        let (callbacks, rx) = found_callbacks();
+        let mut id = 0;
+        assert_eq!(
+            SUCCESS,
+            module_add_event_listener(module, &callbacks, &mut id)
+        );
+        (id, rx)
+    }
+
+    fn assert_silent(rx: &Receiver<u64>) {
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn listeners_can_be_added_and_removed() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            let (first_id, first) = add(module);
+            let (_, second) = add(module);
+            let (callbacks, replaced) = found_callbacks();
+            assert_eq!(SUCCESS, set_event_callbacks_ex(module, &callbacks));
+            let (callbacks, current) = found_callbacks();
+            assert_eq!(SUCCESS, set_event_callbacks_ex(module, &callbacks));
+
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+            for rx in [&first, &second, &current] {
+                assert_eq!(ADDRESS, wait(rx));
+                assert_silent(rx);
+            }
+            assert_silent(&replaced);
+
+            assert_eq!(SUCCESS, module_remove_event_listener(module, first_id));
+            assert_eq!(
+                INVALID_ARGUMENT,
+                module_remove_event_listener(module, first_id)
+            );
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+            assert_eq!(ADDRESS, wait(&second));
+            assert_eq!(ADDRESS, wait(&current));
+            assert_silent(&first);
+            free_module(module);
+        }
+    }
+}
@@ -0,0 +1,1439 @@
+//! Scripted in-memory BLE backend. Modules created with `create_module_mock` talk to virtual
+//! peripherals instead of a radio, which the host drives through the `mock_*` functions.
+
+use crate::backend::{Adapter, CentralEvent, EventStream, NotificationStream, PeripheralId};
+use crate::{
+    CModule, ERROR_DEVICE_NOT_FOUND, ERROR_FAIL, ERROR_INVALID_BD_ADDR, ERROR_NOT_CONNECTED,
+    ERROR_NOT_SUPPORTED, ERROR_NO_SUCH_CHARACTERISTIC, ERROR_PERMISSION_DENIED,
+    ERROR_RUNTIME_ERROR, ERROR_TIMED_OUT, ERROR_UNEXPECTED_CALLBACK,
+    ERROR_UNEXPECTED_CHARACTERISTIC, ERROR_UUID, INVALID_ARGUMENT, SUCCESS,
+};
+use btleplug::api::{
+    BDAddr, CentralState, CharPropFlags, Characteristic, Descriptor, PeripheralProperties,
+    ScanFilter, Service, ValueNotification, WriteType,
+};
+use btleplug::{Error, Result as BleResult};
+use futures::stream;
+use log::{debug, error, trace, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fmt::{Debug, Formatter};
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use uuid::Uuid;
+
+/// Operations whose next invocation can be made to fail with `mock_peripheral_fail_next`, or
+/// with `mock_adapter_fail_next` for the scan operations
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MockOperation {
+    Connect = 0,
+    Disconnect = 1,
+    DiscoverServices = 2,
+    Read = 3,
+    Write = 4,
+    Subscribe = 5,
+    Unsubscribe = 6,
+    ReadDescriptor = 7,
+    WriteDescriptor = 8,
+    StartScan = 9,
+    StopScan = 10,
+}
+
+impl MockOperation {
+    fn from_c_int(operation: c_int) -> Option<MockOperation> {
+        match operation {
+            0 => Some(MockOperation::Connect),
+            1 => Some(MockOperation::Disconnect),
+            2 => Some(MockOperation::DiscoverServices),
+            3 => Some(MockOperation::Read),
+            4 => Some(MockOperation::Write),
+            5 => Some(MockOperation::Subscribe),
+            6 => Some(MockOperation::Unsubscribe),
+            7 => Some(MockOperation::ReadDescriptor),
+            8 => Some(MockOperation::WriteDescriptor),
+            9 => Some(MockOperation::StartScan),
+            10 => Some(MockOperation::StopScan),
+            _ => None,
+        }
+    }
+}
+
+/// Builds the btleplug error that `error_to_result` maps back to `result`
+fn error_from_result(result: c_int) -> Error {
+    match result {
+        ERROR_PERMISSION_DENIED => Error::PermissionDenied,
+        ERROR_DEVICE_NOT_FOUND => Error::DeviceNotFound,
+        ERROR_NOT_CONNECTED => Error::NotConnected,
+        ERROR_UNEXPECTED_CALLBACK => Error::UnexpectedCallback,
+        ERROR_UNEXPECTED_CHARACTERISTIC => Error::UnexpectedCharacteristic,
+        ERROR_NO_SUCH_CHARACTERISTIC => Error::NoSuchCharacteristic,
+        ERROR_NOT_SUPPORTED => Error::NotSupported(String::from("Mock failure")),
+        ERROR_TIMED_OUT => Error::TimedOut(Duration::ZERO),
+        ERROR_UUID => Error::Uuid(Uuid::parse_str("").unwrap_err()),
+        ERROR_INVALID_BD_ADDR => Error::InvalidBDAddr("".parse::<BDAddr>().unwrap_err()),
+        ERROR_RUNTIME_ERROR => Error::RuntimeError(String::from("Mock failure")),
+        _ => Error::Other("Mock failure".into()),
+    }
+}
+
+/// Names of the adapters set with `mock_set_adapters`
+static ADAPTERS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// Fresh mock adapters for the names set with `mock_set_adapters`, if any
+pub(crate) fn installed_adapters() -> Option<Vec<MockAdapter>> {
+    let names = ADAPTERS.lock().unwrap();
+    Some(
+        names
+            .as_ref()?
+            .iter()
+            .map(|n| MockAdapter::new(n))
+            .collect(),
+    )
+}
+
+/// Senders that drop out once their receiving stream goes away
+struct Listeners<T> {
+    senders: Vec<UnboundedSender<T>>,
+}
+
+impl<T: Clone + Send + 'static> Listeners<T> {
+    fn new() -> Self {
+        Listeners {
+            senders: Vec::new(),
+        }
+    }
+
+    fn subscribe(&mut self) -> UnboundedReceiver<T> {
+        let (tx, rx) = unbounded_channel();
+        self.senders.push(tx);
+        rx
+    }
+
+    /// Returns false when nobody was listening
+    fn send(&mut self, item: T) -> bool {
+        self.senders.retain(|s| s.send(item.clone()).is_ok());
+        !self.senders.is_empty()
+    }
+}
+
+fn into_stream<T: Send + 'static>(
+    rx: UnboundedReceiver<T>,
+) -> std::pin::Pin<Box<dyn futures::Stream<Item = T> + Send>> {
+    Box::pin(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    }))
+}
+
+struct AdapterState {
+    peripherals: Vec<MockPeripheral>,
+    listeners: Listeners<CentralEvent>,
+    /// Events raised before anyone listened, handed to the first listener so scripted tests do
+    /// not race the event loop subscribing
+    backlog: Vec<CentralEvent>,
+    scan_filter: Option<ScanFilter>,
+    central_state: CentralState,
+    failures: HashMap<MockOperation, c_int>,
+}
+
+impl AdapterState {
+    fn check(&mut self, operation: MockOperation) -> BleResult<()> {
+        match self.failures.remove(&operation) {
+            Some(result) => Err(error_from_result(result)),
+            None => Ok(()),
+        }
+    }
+}
+
+struct AdapterInner {
+    name: String,
+    state: Mutex<AdapterState>,
+}
+
+impl AdapterInner {
+    fn emit(&self, event: CentralEvent) {
+        let mut state = self.state.lock().unwrap();
+        if !state.listeners.send(event.clone()) {
+            state.backlog.push(event);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct MockAdapter {
+    inner: Arc<AdapterInner>,
+}
+
+impl MockAdapter {
+    pub(crate) fn new(name: &str) -> MockAdapter {
+        MockAdapter {
+            inner: Arc::new(AdapterInner {
+                name: name.to_string(),
+                state: Mutex::new(AdapterState {
+                    peripherals: Vec::new(),
+                    listeners: Listeners::new(),
+                    backlog: Vec::new(),
+                    scan_filter: None,
+                    central_state: CentralState::PoweredOn,
+                    failures: HashMap::new(),
+                }),
+            }),
+        }
+    }
+
+    pub(crate) async fn events(&self) -> BleResult<EventStream> {
+        let mut state = self.inner.state.lock().unwrap();
+        let rx = state.listeners.subscribe();
+        for event in std::mem::take(&mut state.backlog) {
+            state.listeners.send(event);
+        }
+        Ok(into_stream(rx))
+    }
+
+    pub(crate) async fn start_scan(&self, filter: ScanFilter) -> BleResult<()> {
+        let peripherals = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.check(MockOperation::StartScan)?;
+            state.scan_filter = Some(filter);
+            state.peripherals.clone()
+        };
+        for p in peripherals {
+            p.advertise();
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn stop_scan(&self) -> BleResult<()> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.check(MockOperation::StopScan)?;
+        state.scan_filter = None;
+        Ok(())
+    }
+
+    pub(crate) async fn adapter_info(&self) -> BleResult<String> {
+        Ok(self.inner.name.clone())
+    }
+
+    pub(crate) async fn adapter_state(&self) -> BleResult<CentralState> {
+        Ok(self.inner.state.lock().unwrap().central_state.clone())
+    }
+
+    pub(crate) fn fail_next(&self, operation: MockOperation, result: c_int) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.failures.insert(operation, result);
+    }
+
+    pub(crate) fn set_state(&self, central_state: CentralState) {
+        self.inner.state.lock().unwrap().central_state = central_state.clone();
+        self.inner.emit(CentralEvent::StateUpdate(central_state));
+    }
+
+    pub(crate) async fn peripherals(&self) -> BleResult<Vec<MockPeripheral>> {
+        Ok(self.inner.state.lock().unwrap().peripherals.clone())
+    }
+
+    pub(crate) async fn peripheral(&self, address: &BDAddr) -> BleResult<MockPeripheral> {
+        self.find(*address).ok_or(Error::DeviceNotFound)
+    }
+
+    fn find(&self, address: BDAddr) -> Option<MockPeripheral> {
+        let state = self.inner.state.lock().unwrap();
+        state
+            .peripherals
+            .iter()
+            .find(|p| p.inner.address == address)
+            .cloned()
+    }
+
+    /// Adds a virtual peripheral, which is announced right away if a scan is running
+    pub(crate) fn add_peripheral(
+        &self,
+        address: BDAddr,
+        local_name: Option<String>,
+        rssi: Option<i16>,
+    ) -> MockPeripheral {
+        let p = MockPeripheral {
+            inner: Arc::new(PeripheralInner {
+                address,
+                adapter: Arc::downgrade(&self.inner),
+                state: Mutex::new(PeripheralState {
+                    properties: PeripheralProperties {
+                        address,
+                        local_name,
+                        rssi,
+                        ..PeripheralProperties::default()
+                    },
+                    connected: false,
+                    discovered: false,
+                    services: BTreeMap::new(),
+                    values: HashMap::new(),
+                    descriptor_values: HashMap::new(),
+                    subscriptions: HashSet::new(),
+                    failures: HashMap::new(),
+                    latency: Duration::ZERO,
+                }),
+                listeners: Mutex::new(Listeners::new()),
+            }),
+        };
+        self.inner.state.lock().unwrap().peripherals.push(p.clone());
+        p.advertise();
+        p
+    }
+}
+
+struct PeripheralState {
+    properties: PeripheralProperties,
+    connected: bool,
+    discovered: bool,
+    /// Characteristics by service
+    services: BTreeMap<Uuid, BTreeMap<Uuid, Characteristic>>,
+    values: HashMap<(Uuid, Uuid), Vec<u8>>,
+    descriptor_values: HashMap<(Uuid, Uuid, Uuid), Vec<u8>>,
+    subscriptions: HashSet<(Uuid, Uuid)>,
+    /// One-shot failures, as result codes
+    failures: HashMap<MockOperation, c_int>,
+    /// How long every operation takes to complete
+    latency: Duration,
+}
+
+impl PeripheralState {
+    fn check(&mut self, operation: MockOperation) -> BleResult<()> {
+        match self.failures.remove(&operation) {
+            Some(result) => Err(error_from_result(result)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_connected(&mut self, operation: MockOperation) -> BleResult<()> {
+        self.check(operation)?;
+        if !self.connected {
+            return Err(Error::NotConnected);
+        }
+        Ok(())
+    }
+
+    fn characteristic(
+        &self,
+        service_uuid: &Uuid,
+        uuid: &Uuid,
+        required: CharPropFlags,
+    ) -> BleResult<&Characteristic> {
+        let c = self
+            .services
+            .get(service_uuid)
+            .and_then(|s| s.get(uuid))
+            .ok_or(Error::NoSuchCharacteristic)?;
+        if !c.properties.intersects(required) {
+            return Err(Error::NotSupported(format!(
+                "Characteristic {uuid} does not support {required:?}"
+            )));
+        }
+        Ok(c)
+    }
+
+    fn descriptor(&self, descriptor: &Descriptor) -> BleResult<()> {
+        let c = self
+            .services
+            .get(&descriptor.service_uuid)
+            .and_then(|s| s.get(&descriptor.characteristic_uuid))
+            .ok_or(Error::NoSuchCharacteristic)?;
+        if !c.descriptors.contains(descriptor) {
+            return Err(Error::NoSuchCharacteristic);
+        }
+        Ok(())
+    }
+}
+
+struct PeripheralInner {
+    address: BDAddr,
+    adapter: Weak<AdapterInner>,
+    state: Mutex<PeripheralState>,
+    listeners: Mutex<Listeners<ValueNotification>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct MockPeripheral {
+    inner: Arc<PeripheralInner>,
+}
+
+impl Debug for MockPeripheral {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockPeripheral")
+            .field("address", &self.inner.address)
+            .finish()
+    }
+}
+
+impl MockPeripheral {
+    fn id(&self) -> PeripheralId {
+        PeripheralId::Mock(self.inner.address)
+    }
+
+    fn emit(&self, event: CentralEvent) {
+        if let Some(adapter) = self.inner.adapter.upgrade() {
+            adapter.emit(event);
+        }
+    }
+
+    /// Raises the advertisement events for this peripheral if the adapter is scanning and the
+    /// scan filter lets it through
+    fn advertise(&self) {
+        let Some(adapter) = self.inner.adapter.upgrade() else {
+            return;
+        };
+        let Some(filter) = adapter.state.lock().unwrap().scan_filter.clone() else {
+            return;
+        };
+        let properties = self.inner.state.lock().unwrap().properties.clone();
+        if !filter.services.is_empty()
+            && !filter
+                .services
+                .iter()
+                .any(|s| properties.services.contains(s))
+        {
+            trace!("{} filtered out of scan", self.inner.address);
+            return;
+        }
+
+        adapter.emit(CentralEvent::DeviceDiscovered(self.id()));
+        if !properties.services.is_empty() {
+            adapter.emit(CentralEvent::ServicesAdvertisement {
+                id: self.id(),
+                services: properties.services,
+            });
+        }
+        if !properties.manufacturer_data.is_empty() {
+            adapter.emit(CentralEvent::ManufacturerDataAdvertisement {
+                id: self.id(),
+                manufacturer_data: properties.manufacturer_data,
+            });
+        }
+        if !properties.service_data.is_empty() {
+            adapter.emit(CentralEvent::ServiceDataAdvertisement {
+                id: self.id(),
+                service_data: properties.service_data,
+            });
+        }
+    }
+
+    pub(crate) fn address(&self) -> BDAddr {
+        self.inner.address
+    }
+
+    pub(crate) async fn properties(&self) -> BleResult<Option<PeripheralProperties>> {
+        Ok(Some(self.inner.state.lock().unwrap().properties.clone()))
+    }
+
+    pub(crate) fn services(&self) -> BTreeSet<Service> {
+        let state = self.inner.state.lock().unwrap();
+        if !state.discovered {
+            return BTreeSet::new();
+        }
+        state
+            .services
+            .iter()
+            .map(|(uuid, characteristics)| Service {
+                uuid: *uuid,
+                primary: true,
+                characteristics: characteristics.values().cloned().collect(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn characteristics(&self) -> BTreeSet<Characteristic> {
+        self.services()
+            .into_iter()
+            .flat_map(|s| s.characteristics)
+            .collect()
+    }
+
+    async fn delay(&self) {
+        let latency = self.inner.state.lock().unwrap().latency;
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+    }
+
+    pub(crate) async fn is_connected(&self) -> BleResult<bool> {
+        self.delay().await;
+        Ok(self.inner.state.lock().unwrap().connected)
+    }
+
+    pub(crate) async fn connect(&self) -> BleResult<()> {
+        self.delay().await;
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            state.check(MockOperation::Connect)?;
+            if state.connected {
+                return Ok(());
+            }
+            state.connected = true;
+        }
+        self.emit(CentralEvent::DeviceConnected(self.id()));
+        Ok(())
+    }
+
+    pub(crate) async fn disconnect(&self) -> BleResult<()> {
+        self.delay().await;
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .check(MockOperation::Disconnect)?;
+        self.drop_connection();
+        Ok(())
+    }
+
+    pub(crate) async fn discover_services(&self) -> BleResult<()> {
+        self.delay().await;
+        let mut state = self.inner.state.lock().unwrap();
+        state.check_connected(MockOperation::DiscoverServices)?;
+        state.discovered = true;
+        Ok(())
+    }
+
+    pub(crate) async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> BleResult<()> {
+        self.delay().await;
+        let mut state = self.inner.state.lock().unwrap();
+        state.check_connected(MockOperation::Write)?;
+        let required = match write_type {
+            WriteType::WithResponse => CharPropFlags::WRITE,
+            WriteType::WithoutResponse => CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        };
+        state.characteristic(&characteristic.service_uuid, &characteristic.uuid, required)?;
+        state.values.insert(
+            (characteristic.service_uuid, characteristic.uuid),
+            data.to_vec(),
+        );
+        Ok(())
+    }
+
+    pub(crate) async fn read(&self, characteristic: &Characteristic) -> BleResult<Vec<u8>> {
+        self.delay().await;
+        let mut state = self.inner.state.lock().unwrap();
+        state.check_connected(MockOperation::Read)?;
+        state.characteristic(
+            &characteristic.service_uuid,
+            &characteristic.uuid,
+            CharPropFlags::READ,
+        )?;
+        Ok(state
+            .values
+            .get(&(characteristic.service_uuid, characteristic.uuid))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    pub(crate) async fn subscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        self.delay().await;
+        let mut state = self.inner.state.lock().unwrap();
+        state.check_connected(MockOperation::Subscribe)?;
+        state.characteristic(
+            &characteristic.service_uuid,
+            &characteristic.uuid,
+            CharPropFlags::NOTIFY | CharPropFlags::INDICATE,
+        )?;
+        state
+            .subscriptions
+            .insert((characteristic.service_uuid, characteristic.uuid));
+        Ok(())
+    }
+
+    pub(crate) async fn unsubscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        self.delay().await;
+        let mut state = self.inner.state.lock().unwrap();
+        state.check_connected(MockOperation::Unsubscribe)?;
+        state.characteristic(
+            &characteristic.service_uuid,
+            &characteristic.uuid,
+            CharPropFlags::NOTIFY | CharPropFlags::INDICATE,
+        )?;
+        state
+            .subscriptions
+            .remove(&(characteristic.service_uuid, characteristic.uuid));
+        Ok(())
+    }
+
+    pub(crate) async fn notifications(&self) -> BleResult<NotificationStream> {
+        Ok(into_stream(
+            self.inner.listeners.lock().unwrap().subscribe(),
+        ))
+    }
+
+    pub(crate) async fn write_descriptor(
+        &self,
+        descriptor: &Descriptor,
+        data: &[u8],
+    ) -> BleResult<()> {
+        self.delay().await;
+        let mut state = self.inner.state.lock().unwrap();
+        state.check_connected(MockOperation::WriteDescriptor)?;
+        state.descriptor(descriptor)?;
+        state.descriptor_values.insert(
+            (
+                descriptor.service_uuid,
+                descriptor.characteristic_uuid,
+                descriptor.uuid,
+            ),
+            data.to_vec(),
+        );
+        Ok(())
+    }
+
+    pub(crate) async fn read_descriptor(&self, descriptor: &Descriptor) -> BleResult<Vec<u8>> {
+        self.delay().await;
+        let mut state = self.inner.state.lock().unwrap();
+        state.check_connected(MockOperation::ReadDescriptor)?;
+        state.descriptor(descriptor)?;
+        Ok(state
+            .descriptor_values
+            .get(&(
+                descriptor.service_uuid,
+                descriptor.characteristic_uuid,
+                descriptor.uuid,
+            ))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Replaces the services in the advertisement, announcing them if a scan is running. As on
+    /// platforms which only report the latest advertisement, earlier services are forgotten.
+    pub(crate) fn advertise_services(&self, services: &[Uuid]) {
+        self.inner.state.lock().unwrap().properties.services = services.to_vec();
+        self.advertise();
+    }
+
+    pub(crate) fn set_manufacturer_data(&self, manufacturer_id: u16, data: &[u8]) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .properties
+            .manufacturer_data
+            .insert(manufacturer_id, data.to_vec());
+        self.advertise();
+    }
+
+    pub(crate) fn set_service_data(&self, service_uuid: Uuid, data: &[u8]) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .properties
+            .service_data
+            .insert(service_uuid, data.to_vec());
+        self.advertise();
+    }
+
+    /// Changes the signal strength, reporting the update if a scan is running
+    pub(crate) fn set_rssi(&self, rssi: i16) {
+        self.inner.state.lock().unwrap().properties.rssi = Some(rssi);
+        let Some(adapter) = self.inner.adapter.upgrade() else {
+            return;
+        };
+        if adapter.state.lock().unwrap().scan_filter.is_some() {
+            adapter.emit(CentralEvent::DeviceUpdated(self.id()));
+        }
+    }
+
+    pub(crate) fn set_tx_power_level(&self, tx_power_level: i16) {
+        self.inner.state.lock().unwrap().properties.tx_power_level = Some(tx_power_level);
+        self.advertise();
+    }
+
+    pub(crate) fn add_characteristic(
+        &self,
+        service_uuid: Uuid,
+        uuid: Uuid,
+        properties: CharPropFlags,
+    ) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.services.entry(service_uuid).or_default().insert(
+            uuid,
+            Characteristic {
+                uuid,
+                service_uuid,
+                properties,
+                descriptors: BTreeSet::new(),
+            },
+        );
+    }
+
+    pub(crate) fn add_descriptor(
+        &self,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+        uuid: Uuid,
+    ) -> BleResult<()> {
+        let mut state = self.inner.state.lock().unwrap();
+        let c = state
+            .services
+            .get_mut(&service_uuid)
+            .and_then(|s| s.get_mut(&characteristic_uuid))
+            .ok_or(Error::NoSuchCharacteristic)?;
+        c.descriptors.insert(Descriptor {
+            uuid,
+            service_uuid,
+            characteristic_uuid,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn set_value(&self, service_uuid: Uuid, uuid: Uuid, data: &[u8]) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .values
+            .insert((service_uuid, uuid), data.to_vec());
+    }
+
+    pub(crate) fn set_descriptor_value(
+        &self,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+        uuid: Uuid,
+        data: &[u8],
+    ) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .descriptor_values
+            .insert((service_uuid, characteristic_uuid, uuid), data.to_vec());
+    }
+
+    /// Updates a value and notifies listeners if the characteristic is subscribed
+    pub(crate) fn notify(&self, service_uuid: Uuid, uuid: Uuid, data: &[u8]) {
+        let subscribed = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.values.insert((service_uuid, uuid), data.to_vec());
+            state.connected && state.subscriptions.contains(&(service_uuid, uuid))
+        };
+        if !subscribed {
+            debug!("{service_uuid}:{uuid} is not subscribed, dropping notification");
+            return;
+        }
+        self.inner
+            .listeners
+            .lock()
+            .unwrap()
+            .send(ValueNotification {
+                uuid,
+                value: data.to_vec(),
+            });
+    }
+
+    /// Simulates the link going down without the host asking for it
+    pub(crate) fn drop_connection(&self) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if !state.connected {
+                return;
+            }
+            state.connected = false;
+            state.subscriptions.clear();
+        }
+        self.emit(CentralEvent::DeviceDisconnected(self.id()));
+    }
+
+    pub(crate) fn set_latency(&self, latency: Duration) {
+        self.inner.state.lock().unwrap().latency = latency;
+    }
+
+    pub(crate) fn fail_next(&self, operation: MockOperation, result: c_int) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .failures
+            .insert(operation, result);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn create_module_mock(module: *mut *mut CModule) -> c_int {
+    trace!("Enter: create_module_mock");
+    *module = null_mut();
+
+    let runtime = match Runtime::new() {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Failed to initialize tokio::Runtime {:?}", e);
+            *module = Box::into_raw(Box::new(CModule::new(None, None)));
+            crate::set_error_string(&*module, CString::new(e.to_string()).unwrap());
+            return ERROR_FAIL;
+        }
+    };
+
+    let adapter = Adapter::Mock(MockAdapter::new("Mock adapter"));
+    *module = Box::into_raw(Box::new(CModule::new(Some(runtime), Some(adapter))));
+    trace!("Success: create_module_mock");
+    SUCCESS
+}
+
+/// Makes `list_adapters`, `create_module` and `create_module_with_adapter` enumerate mock adapters
+/// with these names instead of the platform's. A null `names` restores the platform adapters.
+#[no_mangle]
+pub unsafe extern "C" fn mock_set_adapters(names: *const *const c_char, count: c_int) -> c_int {
+    trace!("Enter: mock_set_adapters");
+    if names.is_null() {
+        *ADAPTERS.lock().unwrap() = None;
+        return SUCCESS;
+    }
+    if count < 0 {
+        error!("invalid adapter count {count}");
+        return INVALID_ARGUMENT;
+    }
+
+    let names = from_raw_parts(names, count as usize)
+        .iter()
+        .map(|n| CStr::from_ptr(*n).to_string_lossy().into_owned())
+        .collect();
+    *ADAPTERS.lock().unwrap() = Some(names);
+    SUCCESS
+}
+
+unsafe fn get_mock_adapter(module: *mut CModule) -> Result<MockAdapter, c_int> {
+    if module.is_null() {
+        error!("null module");
+        return Err(INVALID_ARGUMENT);
+    }
+
+    let m = &(*module).module;
+    match &m.adapter {
+        Some(Adapter::Mock(a)) => Ok(a.clone()),
+        _ => {
+            error!("not a mock module");
+            crate::set_error_str(&module, "Not a mock module");
+            Err(INVALID_ARGUMENT)
+        }
+    }
+}
+
+unsafe fn get_mock_peripheral(module: *mut CModule, address: u64) -> Result<MockPeripheral, c_int> {
+    let adapter = get_mock_adapter(module)?;
+    let Ok(addr) = BDAddr::try_from(address) else {
+        crate::set_error_str(&module, "Invalid address");
+        return Err(ERROR_INVALID_BD_ADDR);
+    };
+    match adapter.find(addr) {
+        Some(p) => Ok(p),
+        None => {
+            error!("no mock peripheral {addr}");
+            crate::set_error_str(&module, "Unknown mock peripheral");
+            Err(ERROR_DEVICE_NOT_FOUND)
+        }
+    }
+}
+
+unsafe fn data_slice<'a>(data: *const u8, data_length: u32) -> &'a [u8] {
+    if data.is_null() {
+        return &[];
+    }
+    from_raw_parts(data, data_length as usize)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_add_peripheral(
+    module: *mut CModule,
+    address: u64,
+    local_name: *const c_char,
+    rssi: i16,
+) -> c_int {
+    trace!("Enter: mock_add_peripheral");
+    let adapter = match get_mock_adapter(module) {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+    let Ok(addr) = BDAddr::try_from(address) else {
+        crate::set_error_str(&module, "Invalid address");
+        return ERROR_INVALID_BD_ADDR;
+    };
+    if adapter.find(addr).is_some() {
+        crate::set_error_str(&module, "Mock peripheral already exists");
+        return INVALID_ARGUMENT;
+    }
+
+    let local_name = if local_name.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(local_name).to_string_lossy().into_owned())
+    };
+    adapter.add_peripheral(addr, local_name, Some(rssi));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_advertise_services(
+    module: *mut CModule,
+    address: u64,
+    services: *const Uuid,
+    service_count: c_int,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    if services.is_null() || service_count < 0 {
+        crate::set_error_str(&module, "Null argument: services");
+        return INVALID_ARGUMENT;
+    }
+    p.advertise_services(from_raw_parts(services, service_count as usize));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_set_manufacturer_data(
+    module: *mut CModule,
+    address: u64,
+    manufacturer_id: u16,
+    data: *const u8,
+    data_length: u32,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.set_manufacturer_data(manufacturer_id, data_slice(data, data_length));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_set_service_data(
+    module: *mut CModule,
+    address: u64,
+    service_uuid: Uuid,
+    data: *const u8,
+    data_length: u32,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.set_service_data(service_uuid, data_slice(data, data_length));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_set_rssi(
+    module: *mut CModule,
+    address: u64,
+    rssi: i16,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.set_rssi(rssi);
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_set_tx_power_level(
+    module: *mut CModule,
+    address: u64,
+    tx_power_level: i16,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.set_tx_power_level(tx_power_level);
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_add_characteristic(
+    module: *mut CModule,
+    address: u64,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    properties: u8,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.add_characteristic(
+        service_uuid,
+        uuid,
+        CharPropFlags::from_bits_truncate(properties),
+    );
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_add_descriptor(
+    module: *mut CModule,
+    address: u64,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    uuid: Uuid,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    match p.add_descriptor(service_uuid, characteristic_uuid, uuid) {
+        Ok(()) => SUCCESS,
+        Err(e) => {
+            crate::set_error(&module, &e);
+            crate::error_to_result(&e)
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_set_value(
+    module: *mut CModule,
+    address: u64,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    data: *const u8,
+    data_length: u32,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.set_value(service_uuid, uuid, data_slice(data, data_length));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_set_descriptor_value(
+    module: *mut CModule,
+    address: u64,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    uuid: Uuid,
+    data: *const u8,
+    data_length: u32,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.set_descriptor_value(
+        service_uuid,
+        characteristic_uuid,
+        uuid,
+        data_slice(data, data_length),
+    );
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_notify(
+    module: *mut CModule,
+    address: u64,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    data: *const u8,
+    data_length: u32,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.notify(service_uuid, uuid, data_slice(data, data_length));
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_drop_connection(
+    module: *mut CModule,
+    address: u64,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.drop_connection();
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_fail_next(
+    module: *mut CModule,
+    address: u64,
+    operation: c_int,
+    result: c_int,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    let Some(operation) = MockOperation::from_c_int(operation) else {
+        crate::set_error_str(&module, "Unknown mock operation");
+        return INVALID_ARGUMENT;
+    };
+    p.fail_next(operation, result);
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_adapter_fail_next(
+    module: *mut CModule,
+    operation: c_int,
+    result: c_int,
+) -> c_int {
+    let a = match get_mock_adapter(module) {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+    let Some(operation) = MockOperation::from_c_int(operation) else {
+        crate::set_error_str(&module, "Unknown mock operation");
+        return INVALID_ARGUMENT;
+    };
+    a.fail_next(operation, result);
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_adapter_set_state(module: *mut CModule, state: c_int) -> c_int {
+    let a = match get_mock_adapter(module) {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+    let state = match state {
+        0 => CentralState::Unknown,
+        1 => CentralState::PoweredOn,
+        2 => CentralState::PoweredOff,
+        _ => {
+            crate::set_error_str(&module, "Unknown adapter state");
+            return INVALID_ARGUMENT;
+        }
+    };
+    a.set_state(state);
+    SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mock_peripheral_set_latency(
+    module: *mut CModule,
+    address: u64,
+    latency_ms: u32,
+) -> c_int {
+    let p = match get_mock_peripheral(module, address) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+    p.set_latency(Duration::from_millis(latency_ms.into()));
+    SUCCESS
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        free_module, free_peripheral, peripheral_connect, peripheral_discover_services,
+        peripheral_get_address, peripheral_read, peripheral_register_notification_events,
+        peripheral_write, set_event_callbacks, start_scan_peripherals, CPeripheral,
+    };
+    use std::ffi::c_void;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    pub(crate) const ADDRESS: u64 = 0x0011_2233_4455;
+    pub(crate) const SERVICE: Uuid = Uuid::from_u128(0x1000);
+    pub(crate) const CHARACTERISTIC: Uuid = Uuid::from_u128(0x1001);
+
+    pub(crate) fn wait<T>(rx: &Receiver<T>) -> T {
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("callback was not invoked")
+    }
+
+    /// User data for a callback which runs once, and takes back ownership of the sender
+    pub(crate) fn once<T>(tx: &Sender<T>) -> *mut c_void {
+        Box::into_raw(Box::new(tx.clone())) as *mut c_void
+    }
+
+    /// User data for a callback which runs repeatedly. The task calling it can outlive the handle
+    /// or module it was started from, so the context is kept for the rest of the test run.
+    pub(crate) fn leak<T>(context: T) -> &'static T {
+        Box::leak(Box::new(context))
+    }
+
+    unsafe fn take_sender<T>(user_data: *mut c_void) -> Box<Sender<T>> {
+        Box::from_raw(user_data as *mut Sender<T>)
+    }
+
+    struct Events {
+        found: Sender<(u64, usize)>,
+        disconnected: Sender<u64>,
+    }
+
+    extern "C" fn on_found(
+        id: u64,
+        peripheral: *mut CPeripheral,
+        _services: *const Uuid,
+        _service_count: c_int,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let events = unsafe { &*(user_data as *const Events) };
+        let _ = events.found.send((id, peripheral as usize));
+        1
+    }
+
+    extern "C" fn on_disconnected(id: u64, user_data: *mut c_void) {
+        let events = unsafe { &*(user_data as *const Events) };
+        let _ = events.disconnected.send(id);
+    }
+
+    /// A `found` callback sending each peripheral's id to the `Sender<u64>` in `user_data`
+    pub(crate) extern "C" fn send_found(
+        id: u64,
+        _peripheral: *mut CPeripheral,
+        _services: *const Uuid,
+        _service_count: c_int,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let found = unsafe { &*(user_data as *const Sender<u64>) };
+        let _ = found.send(id);
+        0
+    }
+
+    pub(crate) extern "C" fn on_completed(result: c_int, user_data: *mut c_void) {
+        let _ = unsafe { take_sender(user_data) }.send(result);
+    }
+
+    pub(crate) extern "C" fn on_read(
+        result: c_int,
+        data: *const u8,
+        len: c_int,
+        user_data: *mut c_void,
+    ) {
+        let value = unsafe { data_slice(data, len as u32) }.to_vec();
+        let _ = unsafe { take_sender(user_data) }.send((result, value));
+    }
+
+    struct Notifications {
+        ready: Sender<c_int>,
+        values: Sender<(u64, Uuid, Uuid, Vec<u8>)>,
+    }
+
+    extern "C" fn on_notify_ready(result: c_int, user_data: *mut c_void) {
+        let notifications = unsafe { &*(user_data as *const Notifications) };
+        let _ = notifications.ready.send(result);
+    }
+
+    extern "C" fn on_notify(
+        id: u64,
+        service_uuid: Uuid,
+        uuid: Uuid,
+        data: *const u8,
+        len: c_int,
+        user_data: *mut c_void,
+    ) {
+        let notifications = unsafe { &*(user_data as *const Notifications) };
+        let value = unsafe { data_slice(data, len as u32) }.to_vec();
+        let _ = notifications.values.send((id, service_uuid, uuid, value));
+    }
+
+    /// Registers for the peripheral's notifications and waits until they are being listened for
+    pub(crate) unsafe fn listen(
+        peripheral: *mut CPeripheral,
+    ) -> Receiver<(u64, Uuid, Uuid, Vec<u8>)> {
+        let (ready_tx, ready_rx) = channel();
+        let (values_tx, values_rx) = channel();
+        let notifications = leak(Notifications {
+            ready: ready_tx,
+            values: values_tx,
+        });
+        assert_eq!(
+            SUCCESS,
+            peripheral_register_notification_events(
+                peripheral,
+                on_notify_ready,
+                on_notify,
+                notifications as *const Notifications as *mut c_void
+            )
+        );
+        assert_eq!(SUCCESS, wait(&ready_rx));
+        values_rx
+    }
+
+    pub(crate) struct Fixture {
+        pub(crate) module: *mut CModule,
+        pub(crate) peripheral: *mut CPeripheral,
+        disconnected: Receiver<u64>,
+    }
+
+    impl Fixture {
+        /// A mock module which has discovered a peripheral with one readable, writable and
+        /// notifying characteristic
+        pub(crate) unsafe fn new() -> Fixture {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            let properties = CharPropFlags::READ | CharPropFlags::WRITE | CharPropFlags::NOTIFY;
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_add_characteristic(
+                    module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    properties.bits()
+                )
+            );
+
+            let (found_tx, found_rx) = channel();
+            let (disconnected_tx, disconnected_rx) = channel();
+            let events = leak(Events {
+                found: found_tx,
+                disconnected: disconnected_tx,
+            });
+            let user_data = events as *const Events as *mut c_void;
+            assert_eq!(
+                SUCCESS,
+                set_event_callbacks(module, on_found, on_disconnected, user_data)
+            );
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+
+            let (id, peripheral) = wait(&found_rx);
+            assert_eq!(ADDRESS, id);
+            Fixture {
+                module,
+                peripheral: peripheral as *mut CPeripheral,
+                disconnected: disconnected_rx,
+            }
+        }
+
+        pub(crate) unsafe fn connect(&self) {
+            let (tx, rx) = channel();
+            assert_eq!(
+                SUCCESS,
+                peripheral_connect(self.peripheral, 0, on_completed, once(&tx), null_mut())
+            );
+            assert_eq!(SUCCESS, wait(&rx));
+            assert_eq!(
+                SUCCESS,
+                peripheral_discover_services(
+                    self.peripheral,
+                    0,
+                    on_completed,
+                    once(&tx),
+                    null_mut()
+                )
+            );
+            assert_eq!(SUCCESS, wait(&rx));
+        }
+
+        unsafe fn read(&self) -> (c_int, Vec<u8>) {
+            let (tx, rx) = channel();
+            assert_eq!(
+                SUCCESS,
+                peripheral_read(
+                    self.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    0,
+                    on_read,
+                    once(&tx),
+                    null_mut()
+                )
+            );
+            wait(&rx)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            unsafe {
+                free_peripheral(self.peripheral);
+                free_module(self.module);
+            }
+        }
+    }
+
+    #[test]
+    fn scan_reports_mock_peripheral() {
+        unsafe {
+            let f = Fixture::new();
+            let mut address = 0;
+            assert_eq!(SUCCESS, peripheral_get_address(f.peripheral, &mut address));
+            assert_eq!(ADDRESS, address);
+        }
+    }
+
+    #[test]
+    fn written_value_can_be_read_back() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+
+            let (tx, rx) = channel();
+            let mut data = [1u8, 2, 3];
+            assert_eq!(
+                SUCCESS,
+                peripheral_write(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    true,
+                    data.as_mut_ptr(),
+                    data.len() as u32,
+                    0,
+                    on_completed,
+                    once(&tx),
+                    null_mut()
+                )
+            );
+            assert_eq!(SUCCESS, wait(&rx));
+            assert_eq!((SUCCESS, vec![1, 2, 3]), f.read());
+        }
+    }
+
+    #[test]
+    fn operations_require_a_connection() {
+        unsafe {
+            let f = Fixture::new();
+            let (result, value) = f.read();
+            assert_eq!(ERROR_NOT_CONNECTED, result);
+            assert!(value.is_empty());
+        }
+    }
+
+    #[test]
+    fn scripted_failure_is_reported_once() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_fail_next(
+                    f.module,
+                    ADDRESS,
+                    MockOperation::Read as c_int,
+                    ERROR_TIMED_OUT
+                )
+            );
+            assert_eq!(ERROR_TIMED_OUT, f.read().0);
+            assert_eq!(SUCCESS, f.read().0);
+        }
+    }
+
+    #[test]
+    fn dropped_connection_is_reported() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(SUCCESS, mock_peripheral_drop_connection(f.module, ADDRESS));
+            assert_eq!(ADDRESS, wait(&f.disconnected));
+        }
+    }
+}
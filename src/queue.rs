@@ -0,0 +1,888 @@
+//! Optional polling delivery. Once `module_enable_event_queue` has been called, events and
+//! operation completions are queued for the host to collect with `module_poll_event` on a thread
+//! of its choosing, instead of invoking callbacks from the runtime's worker threads.
+
+use crate::connection::{ConnectionCallback, ConnectionState};
+use crate::handles::{release, PeripheralRef};
+use crate::reconnect::ReconnectCallback;
+use crate::{
+    free_raw_slice, into_raw_slice, set_error_str, CModule, CPeripheral, CompletedCallback,
+    IsConnectedCallback, NotifyCallback, PeripheralEventCallback, PeripheralFoundCallback,
+    ReadCallback, UserData, INVALID_ARGUMENT, SUCCESS,
+};
+use log::{error, trace, warn};
+use std::collections::VecDeque;
+use std::ffi::{c_int, c_void};
+use std::io::{pipe, PipeReader, PipeWriter, Read, Write};
+use std::ptr::null_mut;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Upper bound on the queue capacity, which keeps the one byte per queued event written to the
+/// notification pipe within its buffer, with room for the operation results queued beyond it
+const MAX_CAPACITY: u32 = 4096;
+
+type ManufacturerDataCallback = extern "C" fn(
+    id: u64,
+    manufacturer_id: u16,
+    data: *const u8,
+    data_length: c_int,
+    user_data: *mut c_void,
+);
+type ServiceDataCallback = extern "C" fn(
+    id: u64,
+    service_uuid: Uuid,
+    data: *const u8,
+    data_length: c_int,
+    user_data: *mut c_void,
+);
+type StateUpdateCallback = extern "C" fn(state: c_int, user_data: *mut c_void);
+
+/// A callback invocation, either made directly or queued for polling
+pub(crate) enum Event {
+    Found {
+        callback: Option<PeripheralFoundCallback>,
+        id: u64,
+        peripheral: PeripheralRef,
+        services: Vec<Uuid>,
+    },
+    Connected {
+        callback: Option<PeripheralEventCallback>,
+        id: u64,
+    },
+    Disconnected {
+        callback: Option<PeripheralEventCallback>,
+        id: u64,
+    },
+    Updated {
+        callback: Option<PeripheralEventCallback>,
+        id: u64,
+    },
+    ManufacturerData {
+        callback: Option<ManufacturerDataCallback>,
+        id: u64,
+        manufacturer_id: u16,
+        data: Vec<u8>,
+    },
+    ServiceData {
+        callback: Option<ServiceDataCallback>,
+        id: u64,
+        service_uuid: Uuid,
+        data: Vec<u8>,
+    },
+    StateUpdate {
+        callback: Option<StateUpdateCallback>,
+        state: c_int,
+    },
+    Completed {
+        callback: CompletedCallback,
+        result: c_int,
+    },
+    IsConnected {
+        callback: IsConnectedCallback,
+        result: c_int,
+        connected: c_int,
+    },
+    Read {
+        callback: ReadCallback,
+        result: c_int,
+        data: Vec<u8>,
+    },
+    Notification {
+        callback: NotifyCallback,
+        id: u64,
+        service_uuid: Uuid,
+        uuid: Uuid,
+        data: Vec<u8>,
+    },
+    ConnectionState {
+        callback: ConnectionCallback,
+        id: u64,
+        state: ConnectionState,
+        reason: c_int,
+    },
+    Reconnect {
+        callback: Option<ReconnectCallback>,
+        id: u64,
+        attempt: u32,
+        result: c_int,
+    },
+}
+
+impl Event {
+    /// Whether the event carries the result of an operation, which the host would otherwise
+    /// wait on forever
+    fn completes_operation(&self) -> bool {
+        matches!(
+            self,
+            Event::Completed { .. } | Event::IsConnected { .. } | Event::Read { .. }
+        )
+    }
+
+    fn invoke(self, user_data: UserData) {
+        let user_data = user_data.get();
+        match self {
+            Event::Found {
+                callback,
+                id,
+                peripheral,
+                services,
+            } => {
+                let Some(callback) = callback else {
+                    return;
+                };
+                let raw = peripheral.into_raw();
+                let count = services.len() as c_int;
+                if 0 == callback(id, raw, services.as_ptr(), count, user_data) {
+                    // The handle was rejected, release its reference
+                    unsafe { release(raw) };
+                }
+            }
+            Event::Connected { callback, id }
+            | Event::Disconnected { callback, id }
+            | Event::Updated { callback, id } => {
+                if let Some(callback) = callback {
+                    callback(id, user_data);
+                }
+            }
+            Event::ManufacturerData {
+                callback,
+                id,
+                manufacturer_id,
+                data,
+            } => {
+                if let Some(callback) = callback {
+                    let len = data.len() as c_int;
+                    callback(id, manufacturer_id, data.as_ptr(), len, user_data);
+                }
+            }
+            Event::ServiceData {
+                callback,
+                id,
+                service_uuid,
+                data,
+            } => {
+                if let Some(callback) = callback {
+                    let len = data.len() as c_int;
+                    callback(id, service_uuid, data.as_ptr(), len, user_data);
+                }
+            }
+            Event::StateUpdate { callback, state } => {
+                if let Some(callback) = callback {
+                    callback(state, user_data);
+                }
+            }
+            Event::Completed { callback, result } => callback(result, user_data),
+            Event::IsConnected {
+                callback,
+                result,
+                connected,
+            } => callback(result, connected, user_data),
+            Event::Read {
+                callback,
+                result,
+                data,
+            } => {
+                let ptr = if data.is_empty() && result != SUCCESS {
+                    std::ptr::null()
+                } else {
+                    data.as_ptr()
+                };
+                callback(result, ptr, data.len() as c_int, user_data)
+            }
+            Event::Notification {
+                callback,
+                id,
+                service_uuid,
+                uuid,
+                data,
+            } => {
+                let len = data.len() as c_int;
+                callback(id, service_uuid, uuid, data.as_ptr(), len, user_data)
+            }
+            Event::ConnectionState {
+                callback,
+                id,
+                state,
+                reason,
+            } => callback(id, state, reason, user_data),
+            Event::Reconnect {
+                callback,
+                id,
+                attempt,
+                result,
+            } => {
+                if let Some(callback) = callback {
+                    callback(id, attempt, result, user_data);
+                }
+            }
+        }
+    }
+
+    fn into_c(self, user_data: UserData) -> ModuleEvent {
+        let (kind, data) = match self {
+            Event::Found {
+                id,
+                peripheral,
+                services,
+                ..
+            } => {
+                let (services, service_count) = into_raw_slice(services);
+                let found = FoundEvent {
+                    id,
+                    peripheral: peripheral.into_raw(),
+                    services,
+                    service_count,
+                };
+                (EventKind::Found, ModuleEventData { found })
+            }
+            Event::Connected { id, .. } => (
+                EventKind::Connected,
+                ModuleEventData {
+                    peripheral: PeripheralEvent { id },
+                },
+            ),
+            Event::Disconnected { id, .. } => (
+                EventKind::Disconnected,
+                ModuleEventData {
+                    peripheral: PeripheralEvent { id },
+                },
+            ),
+            Event::Updated { id, .. } => (
+                EventKind::Updated,
+                ModuleEventData {
+                    peripheral: PeripheralEvent { id },
+                },
+            ),
+            Event::ManufacturerData {
+                id,
+                manufacturer_id,
+                data,
+                ..
+            } => {
+                let (data, data_length) = into_raw_slice(data);
+                let manufacturer_data = ManufacturerDataEvent {
+                    id,
+                    manufacturer_id,
+                    data,
+                    data_length,
+                };
+                (
+                    EventKind::ManufacturerData,
+                    ModuleEventData { manufacturer_data },
+                )
+            }
+            Event::ServiceData {
+                id,
+                service_uuid,
+                data,
+                ..
+            } => {
+                let (data, data_length) = into_raw_slice(data);
+                let service_data = ServiceDataEvent {
+                    id,
+                    service_uuid,
+                    data,
+                    data_length,
+                };
+                (EventKind::ServiceData, ModuleEventData { service_data })
+            }
+            Event::StateUpdate { state, .. } => (
+                EventKind::StateUpdate,
+                ModuleEventData {
+                    state_update: StateUpdateEvent { state },
+                },
+            ),
+            Event::Completed { result, .. } => (
+                EventKind::Completed,
+                ModuleEventData {
+                    completed: CompletedEvent { result },
+                },
+            ),
+            Event::IsConnected {
+                result, connected, ..
+            } => (
+                EventKind::IsConnected,
+                ModuleEventData {
+                    is_connected: IsConnectedEvent { result, connected },
+                },
+            ),
+            Event::Read { result, data, .. } => {
+                let (data, data_length) = into_raw_slice(data);
+                let read = ReadEvent {
+                    result,
+                    data,
+                    data_length,
+                };
+                (EventKind::Read, ModuleEventData { read })
+            }
+            Event::Notification {
+                id,
+                service_uuid,
+                uuid,
+                data,
+                ..
+            } => {
+                let (data, data_length) = into_raw_slice(data);
+                let notification = NotificationEvent {
+                    id,
+                    service_uuid,
+                    uuid,
+                    data,
+                    data_length,
+                };
+                (EventKind::Notification, ModuleEventData { notification })
+            }
+            Event::ConnectionState {
+                id, state, reason, ..
+            } => {
+                let connection_state = ConnectionStateEvent { id, state, reason };
+                (
+                    EventKind::ConnectionState,
+                    ModuleEventData { connection_state },
+                )
+            }
+            Event::Reconnect {
+                id,
+                attempt,
+                result,
+                ..
+            } => {
+                let reconnect = ReconnectEvent {
+                    id,
+                    attempt,
+                    result,
+                };
+                (EventKind::Reconnect, ModuleEventData { reconnect })
+            }
+        };
+        ModuleEvent {
+            kind,
+            user_data: user_data.get(),
+            data,
+        }
+    }
+}
+
+/// Identifies which member of `ModuleEventData` an event carries
+/// cbindgen:prefix-with-name
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    /// No event arrived before the timeout
+    None = 0,
+    Found = 1,
+    Connected = 2,
+    Disconnected = 3,
+    Updated = 4,
+    ManufacturerData = 5,
+    ServiceData = 6,
+    StateUpdate = 7,
+    Completed = 8,
+    IsConnected = 9,
+    Read = 10,
+    Notification = 11,
+    ConnectionState = 12,
+    Reconnect = 13,
+}
+
+/// The host owns `peripheral` and releases it with `free_peripheral`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FoundEvent {
+    id: u64,
+    peripheral: *mut CPeripheral,
+    services: *mut Uuid,
+    service_count: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PeripheralEvent {
+    id: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ManufacturerDataEvent {
+    id: u64,
+    manufacturer_id: u16,
+    data: *mut u8,
+    data_length: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ServiceDataEvent {
+    id: u64,
+    service_uuid: Uuid,
+    data: *mut u8,
+    data_length: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct StateUpdateEvent {
+    state: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CompletedEvent {
+    result: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IsConnectedEvent {
+    result: c_int,
+    connected: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ReadEvent {
+    result: c_int,
+    data: *mut u8,
+    data_length: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct NotificationEvent {
+    id: u64,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    data: *mut u8,
+    data_length: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConnectionStateEvent {
+    id: u64,
+    state: ConnectionState,
+    reason: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ReconnectEvent {
+    id: u64,
+    attempt: u32,
+    result: c_int,
+}
+
+#[repr(C)]
+pub union ModuleEventData {
+    found: FoundEvent,
+    /// Connected, disconnected and updated events
+    peripheral: PeripheralEvent,
+    manufacturer_data: ManufacturerDataEvent,
+    service_data: ServiceDataEvent,
+    state_update: StateUpdateEvent,
+    completed: CompletedEvent,
+    is_connected: IsConnectedEvent,
+    read: ReadEvent,
+    notification: NotificationEvent,
+    connection_state: ConnectionStateEvent,
+    reconnect: ReconnectEvent,
+}
+
+/// An event collected with `module_poll_event`, released with `free_event`
+#[repr(C)]
+pub struct ModuleEvent {
+    kind: EventKind,
+    /// The user_data passed alongside the callback the event replaces
+    user_data: *mut c_void,
+    data: ModuleEventData,
+}
+
+struct QueueState {
+    events: VecDeque<(UserData, Event)>,
+    capacity: usize,
+    /// Holds one byte per queued event, so the read end polls readable while events are pending
+    reader: PipeReader,
+    writer: PipeWriter,
+}
+
+/// Routes every callback invocation of a module, directly or through the polling queue
+pub(crate) struct EventQueue {
+    state: Mutex<Option<QueueState>>,
+    available: Condvar,
+}
+
+impl EventQueue {
+    pub(crate) fn new() -> EventQueue {
+        EventQueue {
+            state: Mutex::new(None),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Queues the event when polling is enabled, otherwise invokes its callback on this thread
+    pub(crate) fn deliver(&self, user_data: UserData, event: Event) {
+        let mut state = self.state.lock().unwrap();
+        let Some(queue) = state.as_mut() else {
+            drop(state);
+            event.invoke(user_data);
+            return;
+        };
+
+        if queue.events.len() >= queue.capacity && !event.completes_operation() {
+            warn!("Event queue full, dropping event");
+            return;
+        }
+        if let Err(e) = queue.writer.write_all(&[0]) {
+            warn!("Failed to signal event pipe: {e}");
+        }
+        queue.events.push_back((user_data, event));
+        self.available.notify_one();
+    }
+
+    fn enable(&self, capacity: u32) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(queue) = state.as_mut() {
+            queue.capacity = capacity as usize;
+            return Ok(());
+        }
+
+        let (reader, writer) = pipe()?;
+        *state = Some(QueueState {
+            events: VecDeque::new(),
+            capacity: capacity as usize,
+            reader,
+            writer,
+        });
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for an event, or indefinitely for `None`. Fails when polling is not
+    /// enabled.
+    fn poll(&self, timeout: Option<Duration>) -> Result<Option<(UserData, Event)>, ()> {
+        let state = self.state.lock().unwrap();
+        if state.is_none() {
+            return Err(());
+        }
+
+        let empty = |s: &mut Option<QueueState>| s.as_ref().is_some_and(|q| q.events.is_empty());
+        let mut state = match timeout {
+            Some(timeout) => {
+                self.available
+                    .wait_timeout_while(state, timeout, empty)
+                    .unwrap()
+                    .0
+            }
+            None => self.available.wait_while(state, empty).unwrap(),
+        };
+
+        let queue = state.as_mut().unwrap();
+        let event = queue.events.pop_front();
+        if event.is_some() {
+            let mut byte = [0u8];
+            if let Err(e) = queue.reader.read_exact(&mut byte) {
+                warn!("Failed to drain event pipe: {e}");
+            }
+        }
+        Ok(event)
+    }
+
+    /// Drops pending events. Found events own peripheral handles, which keep the module alive.
+    pub(crate) fn clear(&self) {
+        let events = match self.state.lock().unwrap().as_mut() {
+            Some(queue) => {
+                let mut byte = vec![0u8; queue.events.len()];
+                if let Err(e) = queue.reader.read_exact(&mut byte) {
+                    warn!("Failed to drain event pipe: {e}");
+                }
+                std::mem::take(&mut queue.events)
+            }
+            None => return,
+        };
+        // Released after unlocking, as each handle drops its own module reference
+        drop(events);
+    }
+
+    fn fd(&self) -> c_int {
+        #[cfg(unix)]
+        if let Some(queue) = self.state.lock().unwrap().as_ref() {
+            use std::os::fd::AsRawFd;
+            return queue.reader.as_raw_fd();
+        }
+        -1
+    }
+}
+
+/// Switches the module from callbacks to polling. Up to `capacity` events are held, after which
+/// new peripheral and adapter events are dropped. Operation results are always queued, so every
+/// operation still completes.
+#[no_mangle]
+pub unsafe extern "C" fn module_enable_event_queue(module: *mut CModule, capacity: u32) -> c_int {
+    trace!("Enter: module_enable_event_queue");
+    if module.is_null() {
+        error!("null module");
+        return INVALID_ARGUMENT;
+    }
+    if capacity == 0 || capacity > MAX_CAPACITY {
+        error!("Invalid event queue capacity: {capacity}");
+        set_error_str(&module, "Out of range: capacity must be in range 1..4096");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*module).module;
+    if let Err(e) = m.events.enable(capacity) {
+        error!("Failed to create event pipe: {e}");
+        set_error_str(&module, &e.to_string());
+        return crate::ERROR_RUNTIME_ERROR;
+    }
+    trace!("Success: module_enable_event_queue");
+    SUCCESS
+}
+
+/// Waits up to `timeout_ms` for the next event, indefinitely when negative. `event->kind` is
+/// `EVENT_KIND_NONE` when nothing arrived in time.
+#[no_mangle]
+pub unsafe extern "C" fn module_poll_event(
+    module: *mut CModule,
+    event: *mut ModuleEvent,
+    timeout_ms: c_int,
+) -> c_int {
+    if module.is_null() || event.is_null() {
+        error!("null module/event");
+        return INVALID_ARGUMENT;
+    }
+    event.write(ModuleEvent {
+        kind: EventKind::None,
+        user_data: null_mut(),
+        data: ModuleEventData {
+            completed: CompletedEvent { result: SUCCESS },
+        },
+    });
+
+    let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+    let m = &(*module).module;
+    match m.events.poll(timeout) {
+        Ok(Some((user_data, e))) => {
+            *event = e.into_c(user_data);
+            SUCCESS
+        }
+        Ok(None) => SUCCESS,
+        Err(()) => {
+            set_error_str(&module, "Event queue not enabled");
+            INVALID_ARGUMENT
+        }
+    }
+}
+
+/// File descriptor that polls readable while events are queued, for integrating with `select`
+/// or `epoll`. Returns -1 before the queue is enabled and on platforms without one.
+#[no_mangle]
+pub unsafe extern "C" fn module_event_fd(module: *mut CModule) -> c_int {
+    if module.is_null() {
+        return -1;
+    }
+
+    let m = &(*module).module;
+    m.events.fd()
+}
+
+/// Releases the buffers held by an event. Peripheral handles from found events are owned by the
+/// host and are not released.
+#[no_mangle]
+pub unsafe extern "C" fn free_event(event: *mut ModuleEvent) -> c_int {
+    if event.is_null() {
+        return SUCCESS;
+    }
+
+    let e = &mut *event;
+    match e.kind {
+        EventKind::Found => {
+            free_raw_slice(e.data.found.services, e.data.found.service_count);
+        }
+        EventKind::ManufacturerData => {
+            let d = e.data.manufacturer_data;
+            free_raw_slice(d.data, d.data_length);
+        }
+        EventKind::ServiceData => {
+            let d = e.data.service_data;
+            free_raw_slice(d.data, d.data_length);
+        }
+        EventKind::Read => {
+            free_raw_slice(e.data.read.data, e.data.read.data_length);
+        }
+        EventKind::Notification => {
+            let d = e.data.notification;
+            free_raw_slice(d.data, d.data_length);
+        }
+        _ => {}
+    }
+    e.kind = EventKind::None;
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::tests::{Fixture, ADDRESS, CHARACTERISTIC, SERVICE};
+    use crate::mock::{create_module_mock, mock_peripheral_set_rssi, mock_peripheral_set_value};
+    use crate::{
+        free_module, free_peripheral, peripheral_connect, peripheral_read,
+        peripheral_register_notification_events,
+    };
+
+    extern "C" fn on_completed(_result: c_int, _user_data: *mut c_void) {
+        panic!("callback invoked while polling");
+    }
+
+    extern "C" fn on_read(_result: c_int, _data: *const u8, _len: c_int, _user_data: *mut c_void) {
+        panic!("callback invoked while polling");
+    }
+
+    extern "C" fn on_notify(
+        _id: u64,
+        _service_uuid: Uuid,
+        _uuid: Uuid,
+        _data: *const u8,
+        _len: c_int,
+        _user_data: *mut c_void,
+    ) {
+        panic!("callback invoked while polling");
+    }
+
+    /// Polls until an event of the given kind arrives, skipping adapter events such as the
+    /// connection notice that accompanies a connect
+    unsafe fn next(module: *mut CModule, kind: EventKind) -> ModuleEvent {
+        loop {
+            let mut event = std::mem::MaybeUninit::uninit();
+            assert_eq!(SUCCESS, module_poll_event(module, event.as_mut_ptr(), 5000));
+            let mut event = event.assume_init();
+            assert_ne!(EventKind::None, event.kind, "no event was queued");
+            if event.kind == kind {
+                return event;
+            }
+            if event.kind == EventKind::Found {
+                free_peripheral(event.data.found.peripheral);
+            }
+            free_event(&mut event);
+        }
+    }
+
+    #[test]
+    fn completions_are_queued_when_polling() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(SUCCESS, module_enable_event_queue(f.module, 16));
+            let context = 7usize as *mut c_void;
+
+            assert_eq!(
+                SUCCESS,
+                peripheral_connect(f.peripheral, 0, on_completed, context, null_mut())
+            );
+            let event = next(f.module, EventKind::Completed);
+            assert_eq!(context, event.user_data);
+            assert_eq!(SUCCESS, event.data.completed.result);
+
+            let value = [1u8, 2, 3];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_value(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    value.as_ptr(),
+                    3
+                )
+            );
+            assert_eq!(
+                SUCCESS,
+                peripheral_read(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    0,
+                    on_read,
+                    context,
+                    null_mut()
+                )
+            );
+            let mut event = next(f.module, EventKind::Read);
+            assert_eq!(context, event.user_data);
+            let read = event.data.read;
+            assert_eq!(SUCCESS, read.result);
+            assert_eq!(&value, from_raw(read.data, read.data_length));
+            assert_eq!(SUCCESS, free_event(&mut event));
+            assert_eq!(EventKind::None, event.kind);
+
+            assert_eq!(
+                SUCCESS,
+                peripheral_register_notification_events(
+                    f.peripheral,
+                    on_completed,
+                    on_notify,
+                    context
+                )
+            );
+            let event = next(f.module, EventKind::Completed);
+            assert_eq!(SUCCESS, event.data.completed.result);
+        }
+    }
+
+    #[test]
+    fn operation_results_are_queued_when_full() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(SUCCESS, module_enable_event_queue(f.module, 1));
+            for rssi in [-50, -60, -70] {
+                assert_eq!(SUCCESS, mock_peripheral_set_rssi(f.module, ADDRESS, rssi));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(
+                SUCCESS,
+                peripheral_connect(f.peripheral, 0, on_completed, null_mut(), null_mut())
+            );
+
+            // The later updates are dropped, the result is not
+            let mut kinds = Vec::new();
+            while kinds.last() != Some(&EventKind::Completed) {
+                let mut event = std::mem::MaybeUninit::uninit();
+                assert_eq!(
+                    SUCCESS,
+                    module_poll_event(f.module, event.as_mut_ptr(), 5000)
+                );
+                let mut event = event.assume_init();
+                assert_ne!(EventKind::None, event.kind, "no event was queued");
+                kinds.push(event.kind);
+                free_event(&mut event);
+            }
+            assert_eq!(vec![EventKind::Updated, EventKind::Completed], kinds);
+        }
+    }
+
+    unsafe fn from_raw<'a>(data: *const u8, len: c_int) -> &'a [u8] {
+        std::slice::from_raw_parts(data, len as usize)
+    }
+
+    #[test]
+    fn polling_requires_the_queue() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            let mut event = std::mem::MaybeUninit::uninit();
+            assert_eq!(
+                INVALID_ARGUMENT,
+                module_poll_event(module, event.as_mut_ptr(), 0)
+            );
+            assert_eq!(-1, module_event_fd(module));
+            assert_eq!(INVALID_ARGUMENT, module_enable_event_queue(module, 0));
+
+            assert_eq!(SUCCESS, module_enable_event_queue(module, 4));
+            assert_eq!(SUCCESS, module_poll_event(module, event.as_mut_ptr(), 10));
+            assert_eq!(EventKind::None, event.assume_init().kind);
+            #[cfg(unix)]
+            assert!(module_event_fd(module) >= 0);
+            free_module(module);
+        }
+    }
+}
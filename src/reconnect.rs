@@ -0,0 +1,218 @@
+//! Automatic reconnection after a peripheral drops its connection unexpectedly, noticed by the
+//! module event loop.
+
+use crate::queue::Event;
+use crate::{
+    connect, get_long_addr, resubscribe, result_code, CPeripheral, Deadline, PeripheralHandle,
+    UserData, INVALID_ARGUMENT, SUCCESS,
+};
+use log::{debug, error, info, trace};
+use std::collections::hash_map::RandomState;
+use std::ffi::{c_int, c_void};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How `peripheral_set_reconnect_policy` retries a dropped connection
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    /// Attempts made before giving up
+    max_attempts: u32,
+    /// Delay before the first attempt, doubled after each failed one
+    initial_delay_ms: u32,
+    /// Upper bound on the delay between attempts
+    max_delay_ms: u32,
+    /// Up to this percentage of each delay is added at random, so peripherals dropped together
+    /// do not retry in lockstep
+    jitter_percent: u32,
+    /// Timeout for each attempt, covering the connection, service discovery and resubscription.
+    /// 0 waits indefinitely.
+    timeout_ms: u32,
+}
+
+pub(crate) type ReconnectCallback =
+    extern "C" fn(id: u64, attempt: u32, result: c_int, user_data: *mut c_void);
+
+pub(crate) struct Reconnect {
+    pub(crate) policy: ReconnectPolicy,
+    /// The handle the policy was set through, which reconnection stops with once released
+    pub(crate) handle: Weak<PeripheralHandle>,
+    pub(crate) callback: Option<ReconnectCallback>,
+    pub(crate) user_data: UserData,
+    /// Signalled to abandon the reconnection in progress
+    pub(crate) cancel: Option<Arc<Notify>>,
+}
+
+/// Adds up to `percent` of `delay` at random. The randomly keyed std hasher stands in for a
+/// random number generator.
+fn jitter(delay: Duration, percent: u32) -> Duration {
+    let span = delay.as_millis() as u64 * u64::from(percent) / 100;
+    if span == 0 {
+        return delay;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    delay + Duration::from_millis(random % (span + 1))
+}
+
+pub(crate) async fn reconnect(
+    ap: Arc<PeripheralHandle>,
+    policy: ReconnectPolicy,
+    callback: Option<ReconnectCallback>,
+    user_data: UserData,
+    cancel: Arc<Notify>,
+) {
+    let id = ap.peripheral.id();
+    let address = get_long_addr(ap.peripheral.address());
+    let max_delay = Duration::from_millis(policy.max_delay_ms.max(policy.initial_delay_ms).into());
+    let mut delay = Duration::from_millis(policy.initial_delay_ms.into());
+
+    for attempt in 1..=policy.max_attempts {
+        tokio::select! {
+            _ = tokio::time::sleep(jitter(delay, policy.jitter_percent)) => {}
+            _ = cancel.notified() => {
+                debug!("Reconnection to {id} cancelled");
+                return;
+            }
+        }
+
+        info!("Reconnecting to {id}, attempt {attempt}");
+        let result = restore(&ap, Deadline::after(policy.timeout_ms), &cancel).await;
+        let event = Event::Reconnect {
+            callback,
+            id: address,
+            attempt,
+            result,
+        };
+        ap.events.deliver(user_data, event);
+        if result == SUCCESS {
+            break;
+        }
+        delay = (delay * 2).min(max_delay);
+    }
+
+    ap.connections.reconnect_finished(&id, &cancel);
+}
+
+/// Connects, discovers services and subscribes to the characteristics subscribed before the drop
+async fn restore(ap: &PeripheralHandle, deadline: Deadline, cancel: &Notify) -> c_int {
+    let mut result = connect(ap, deadline, Some(cancel)).await;
+    // `connect` has already resubscribed when automatic resubscription is on
+    if result.is_ok() && !ap.connections.auto_resubscribe(&ap.peripheral.id()) {
+        result = resubscribe(ap, deadline, Some(cancel)).await;
+    }
+    result_code(&result)
+}
+
+/// Reconnects the peripheral when its connection drops without `peripheral_disconnect` being
+/// called, replacing any policy set through another handle for the same device. The optional
+/// `callback` receives the result of each attempt; a failed last attempt means reconnection gave
+/// up. A null policy turns reconnection off.
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_set_reconnect_policy(
+    peripheral: *mut CPeripheral,
+    policy: *const ReconnectPolicy,
+    callback: Option<extern "C" fn(id: u64, attempt: u32, result: c_int, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> c_int {
+    trace!("Enter: peripheral_set_reconnect_policy");
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return INVALID_ARGUMENT;
+    }
+
+    let ap = &(*peripheral).p;
+    let reconnect = policy.as_ref().map(|policy| Reconnect {
+        policy: *policy,
+        handle: Arc::downgrade(ap),
+        callback,
+        user_data: UserData(user_data),
+        cancel: None,
+    });
+    ap.connections.set_reconnect(&ap.peripheral.id(), reconnect);
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::tests::{leak, listen, wait, Fixture, ADDRESS, CHARACTERISTIC, SERVICE};
+    use crate::mock::{
+        mock_peripheral_drop_connection, mock_peripheral_fail_next, mock_peripheral_notify,
+        MockOperation,
+    };
+    use crate::sync::{peripheral_is_connected_sync, peripheral_subscribe_sync};
+    use crate::ERROR_TIMED_OUT;
+    use std::sync::mpsc::{channel, Sender};
+
+    type Attempt = (u64, u32, c_int);
+
+    extern "C" fn on_attempt(id: u64, attempt: u32, result: c_int, user_data: *mut c_void) {
+        let attempts = unsafe { &*(user_data as *const Sender<Attempt>) };
+        let _ = attempts.send((id, attempt, result));
+    }
+
+    #[test]
+    fn dropped_connection_is_restored() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            let values = listen(f.peripheral);
+
+            let policy = ReconnectPolicy {
+                max_attempts: 3,
+                initial_delay_ms: 1,
+                max_delay_ms: 10,
+                jitter_percent: 50,
+                timeout_ms: 1000,
+            };
+            let (tx, rx) = channel::<Attempt>();
+            assert_eq!(
+                SUCCESS,
+                peripheral_set_reconnect_policy(
+                    f.peripheral,
+                    &policy,
+                    Some(on_attempt),
+                    leak(tx) as *const Sender<Attempt> as *mut c_void
+                )
+            );
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_fail_next(
+                    f.module,
+                    ADDRESS,
+                    MockOperation::Connect as c_int,
+                    ERROR_TIMED_OUT
+                )
+            );
+            assert_eq!(SUCCESS, mock_peripheral_drop_connection(f.module, ADDRESS));
+            assert_eq!((ADDRESS, 1, ERROR_TIMED_OUT), wait(&rx));
+            assert_eq!((ADDRESS, 2, SUCCESS), wait(&rx));
+
+            let mut connected = 0;
+            assert_eq!(
+                SUCCESS,
+                peripheral_is_connected_sync(f.peripheral, &mut connected, 0)
+            );
+            assert_eq!(1, connected);
+            let data = [42u8];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_notify(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    data.as_ptr(),
+                    1
+                )
+            );
+            assert_eq!(vec![42], wait(&values).3);
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! Scan filters applied by the event loop, for criteria the platform `ScanFilter` cannot
+//! express. They only decide which peripherals reach the `found` callback.
+
+use crate::queue::Event;
+use crate::{
+    error_into_cstring, error_to_result, scan_filter, start_scan, CModule, CompletedCallback,
+    ModuleInt, UserData, ERROR_CANCELLED, INVALID_ARGUMENT, SUCCESS,
+};
+use btleplug::api::{PeripheralProperties, ScanFilter};
+use log::{error, trace};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Options for `start_scan_with_options`. Zeroed fields disable their filter.
+#[repr(C)]
+pub struct ScanOptions {
+    /// Passed to the platform as with `start_scan_peripherals`
+    service_uuids: *const Uuid,
+    service_uuid_count: c_int,
+    /// Unless null, only peripherals whose local name starts with this prefix are reported
+    name_prefix: *const c_char,
+    /// When set, only peripherals advertising manufacturer data under `manufacturer_id` are
+    /// reported
+    has_manufacturer_id: bool,
+    manufacturer_id: u16,
+    /// Unless 0, peripherals whose signal is weaker than this many dBm are not reported
+    min_rssi: c_int,
+    /// Unless 0, a peripheral is reported at most once within this many milliseconds
+    dedupe_window_ms: u32,
+}
+
+#[derive(Default)]
+pub(crate) struct ScanState {
+    /// Shared with the event loop, which applies them without holding the lock
+    pub(crate) rules: Arc<ScanRules>,
+    pub(crate) scanning: bool,
+    /// Signalled when a scan started with `start_scan_for` is stopped early
+    pub(crate) timer: Option<Arc<Notify>>,
+}
+
+impl ScanState {
+    /// Records that the scan has stopped, ending a scan started with `start_scan_for` early
+    pub(crate) fn stop(&mut self) {
+        self.scanning = false;
+        if let Some(timer) = self.timer.take() {
+            timer.notify_one();
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ScanRules {
+    name_prefix: Option<String>,
+    manufacturer_id: Option<u16>,
+    min_rssi: Option<i16>,
+    dedupe_window: Duration,
+}
+
+impl ScanRules {
+    /// Whether the `found` callback should hear about the peripheral, recording when it last did
+    /// in `last_found`
+    pub(crate) fn admit(
+        &self,
+        properties: Option<&PeripheralProperties>,
+        address: u64,
+        last_found: &mut HashMap<u64, Instant>,
+    ) -> bool {
+        if self.name_prefix.is_some() || self.manufacturer_id.is_some() || self.min_rssi.is_some() {
+            let Some(properties) = properties else {
+                return false;
+            };
+            if let Some(prefix) = &self.name_prefix {
+                if !properties
+                    .local_name
+                    .as_ref()
+                    .is_some_and(|name| name.starts_with(prefix.as_str()))
+                {
+                    return false;
+                }
+            }
+            if let Some(id) = self.manufacturer_id {
+                if !properties.manufacturer_data.contains_key(&id) {
+                    return false;
+                }
+            }
+            if let Some(min_rssi) = self.min_rssi {
+                if properties.rssi.is_none_or(|rssi| rssi < min_rssi) {
+                    return false;
+                }
+            }
+        }
+
+        if self.dedupe_window.is_zero() {
+            return true;
+        }
+        let now = Instant::now();
+        if let Some(last) = last_found.get(&address) {
+            if now.duration_since(*last) < self.dedupe_window {
+                trace!("Suppressing duplicate of {address:x}");
+                return false;
+            }
+        }
+        last_found.insert(address, now);
+        true
+    }
+}
+
+/// Like `start_scan_peripherals`, additionally filtering the peripherals reported to the `found`
+/// callback. A null `options` scans without filters.
+#[no_mangle]
+pub unsafe extern "C" fn start_scan_with_options(
+    module: *mut CModule,
+    options: *const ScanOptions,
+) -> c_int {
+    trace!("Enter: start_scan_with_options");
+    if module.is_null() {
+        error!("null module");
+        return INVALID_ARGUMENT;
+    }
+
+    match parse_options(module, options) {
+        Ok((filter, rules)) => start_scan(module, filter, rules, None),
+        Err(code) => code,
+    }
+}
+
+/// Like `start_scan_with_options`, stopping the scan after `duration_ms`. `done_callback`
+/// receives `SUCCESS` then, or `ERROR_CANCELLED` when `stop_scan_peripherals` got there first. If
+/// the adapter fails to stop it, the scan keeps running and `done_callback` receives the error.
+#[no_mangle]
+pub unsafe extern "C" fn start_scan_for(
+    module: *mut CModule,
+    options: *const ScanOptions,
+    duration_ms: u32,
+    done_callback: CompletedCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    trace!("Enter: start_scan_for");
+    if module.is_null() {
+        error!("null module");
+        return INVALID_ARGUMENT;
+    }
+
+    let (filter, rules) = match parse_options(module, options) {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    let timer = Arc::new(Notify::new());
+    let result = start_scan(module, filter, rules, Some(timer.clone()));
+    if result != SUCCESS {
+        return result;
+    }
+
+    let m = &(*module).module;
+    let weak = Arc::downgrade(m);
+    let user_data = UserData(user_data);
+    let duration = Duration::from_millis(duration_ms.into());
+    m.runtime.as_ref().unwrap().spawn(async move {
+        let expired = tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = timer.notified() => false,
+        };
+        let Some(m) = weak.upgrade() else {
+            return;
+        };
+        let result = if expired {
+            finish_scan(&m, &timer).await
+        } else {
+            ERROR_CANCELLED
+        };
+        let event = Event::Completed {
+            callback: done_callback,
+            result,
+        };
+        m.events.deliver(user_data, event);
+    });
+
+    trace!("Success: start_scan_for");
+    SUCCESS
+}
+
+/// Stops a scan whose timer expired, unless it was stopped in the meantime. A scan the adapter
+/// fails to stop stays running.
+async fn finish_scan(m: &ModuleInt, timer: &Arc<Notify>) -> c_int {
+    {
+        let mut scan = m.scan.lock().unwrap();
+        if !scan.timer.as_ref().is_some_and(|t| Arc::ptr_eq(t, timer)) {
+            return ERROR_CANCELLED;
+        }
+        scan.timer = None;
+    }
+
+    match m.adapter.as_ref().unwrap().stop_scan().await {
+        Ok(_) => {
+            m.scan.lock().unwrap().scanning = false;
+            SUCCESS
+        }
+        Err(e) => {
+            error!("error in stop_scan: {:?}", e);
+            *m.last_error.lock().unwrap() = error_into_cstring(&e);
+            error_to_result(&e)
+        }
+    }
+}
+
+/// Reports whether a scan is running, as 1 or 0
+#[no_mangle]
+pub unsafe extern "C" fn module_is_scanning(module: *mut CModule, scanning: *mut c_int) -> c_int {
+    if module.is_null() || scanning.is_null() {
+        error!("null module/scanning");
+        return INVALID_ARGUMENT;
+    }
+
+    let m = &(*module).module;
+    *scanning = c_int::from(m.scan.lock().unwrap().scanning);
+    SUCCESS
+}
+
+unsafe fn parse_options(
+    module: *mut CModule,
+    options: *const ScanOptions,
+) -> Result<(ScanFilter, ScanRules), c_int> {
+    let Some(options) = options.as_ref() else {
+        return Ok((ScanFilter::default(), ScanRules::default()));
+    };
+
+    let filter = scan_filter(module, options.service_uuids, options.service_uuid_count)?;
+    let name_prefix = if options.name_prefix.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(options.name_prefix).to_str() {
+            Ok(prefix) => Some(prefix.to_owned()),
+            Err(_) => {
+                crate::set_error_str(&module, "Invalid argument: name_prefix is not valid UTF-8");
+                return Err(INVALID_ARGUMENT);
+            }
+        }
+    };
+    let manufacturer_id = options
+        .has_manufacturer_id
+        .then_some(options.manufacturer_id);
+    let min_rssi = match options.min_rssi {
+        0 => None,
+        rssi => Some(rssi.clamp(i16::MIN.into(), i16::MAX.into()) as i16),
+    };
+
+    let rules = ScanRules {
+        name_prefix,
+        manufacturer_id,
+        min_rssi,
+        dedupe_window: Duration::from_millis(options.dedupe_window_ms.into()),
+    };
+    Ok((filter, rules))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::tests::{leak, on_completed, once, send_found, wait};
+    use crate::mock::{
+        create_module_mock, mock_adapter_fail_next, mock_adapter_set_state, mock_add_peripheral,
+        mock_peripheral_set_manufacturer_data, MockOperation,
+    };
+    use crate::{
+        free_module, set_event_callbacks, start_scan_peripherals, stop_scan_peripherals,
+        ERROR_ALREADY_SCANNING, ERROR_RUNTIME_ERROR,
+    };
+    use std::ffi::c_void;
+    use std::ptr::{null, null_mut};
+    use std::sync::mpsc::{channel, Sender};
+
+    extern "C" fn on_disconnected(_id: u64, _user_data: *mut c_void) {}
+
+    #[test]
+    fn scan_options_filter_found_peripherals() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            for (address, name, rssi) in [
+                (1, c"Sensor-1", -40),
+                (2, c"Other", -40),
+                (3, c"Sensor-2", -90),
+            ] {
+                assert_eq!(
+                    SUCCESS,
+                    mock_add_peripheral(module, address, name.as_ptr(), rssi)
+                );
+            }
+
+            let (tx, rx) = channel::<u64>();
+            let found = leak(tx) as *const Sender<u64> as *mut c_void;
+            assert_eq!(
+                SUCCESS,
+                set_event_callbacks(module, send_found, on_disconnected, found)
+            );
+
+            let options = ScanOptions {
+                service_uuids: null(),
+                service_uuid_count: 0,
+                name_prefix: c"Sensor".as_ptr(),
+                has_manufacturer_id: false,
+                manufacturer_id: 0,
+                min_rssi: -60,
+                dedupe_window_ms: 60_000,
+            };
+            assert_eq!(SUCCESS, start_scan_with_options(module, &options));
+            assert_eq!(1, wait(&rx));
+            // Scanning again advertises every peripheral once more
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+            assert_eq!(SUCCESS, start_scan_with_options(module, &options));
+            assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+            free_module(module);
+        }
+    }
+
+    #[test]
+    fn zeroed_scan_options_filter_nothing() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            for address in [1, 2] {
+                assert_eq!(
+                    SUCCESS,
+                    mock_add_peripheral(module, address, c"Sensor".as_ptr(), -40)
+                );
+            }
+            let data = [1u8];
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_manufacturer_data(module, 2, 0, data.as_ptr(), 1)
+            );
+
+            let (tx, rx) = channel::<u64>();
+            let found = leak(tx) as *const Sender<u64> as *mut c_void;
+            assert_eq!(
+                SUCCESS,
+                set_event_callbacks(module, send_found, on_disconnected, found)
+            );
+            let mut options: ScanOptions = std::mem::zeroed();
+            assert_eq!(SUCCESS, start_scan_with_options(module, &options));
+            let mut addresses = [wait(&rx), wait(&rx)];
+            addresses.sort();
+            assert_eq!([1, 2], addresses);
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+
+            // Company id 0 is a valid filter once enabled
+            options.has_manufacturer_id = true;
+            assert_eq!(SUCCESS, start_scan_with_options(module, &options));
+            assert_eq!(2, wait(&rx));
+            assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+            free_module(module);
+        }
+    }
+
+    unsafe fn is_scanning(module: *mut CModule) -> c_int {
+        let mut scanning = -1;
+        assert_eq!(SUCCESS, module_is_scanning(module, &mut scanning));
+        scanning
+    }
+
+    #[test]
+    fn scans_can_stop_themselves() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(0, is_scanning(module));
+
+            let (tx, rx) = channel();
+            assert_eq!(
+                SUCCESS,
+                start_scan_for(module, null(), 10, on_completed, once(&tx))
+            );
+            assert_eq!(1, is_scanning(module));
+            assert_eq!(
+                ERROR_ALREADY_SCANNING,
+                start_scan_peripherals(module, null_mut(), 0)
+            );
+            assert_eq!(SUCCESS, wait(&rx));
+            assert_eq!(0, is_scanning(module));
+
+            assert_eq!(
+                SUCCESS,
+                start_scan_for(module, null(), 60_000, on_completed, once(&tx))
+            );
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+            assert_eq!(ERROR_CANCELLED, wait(&rx));
+            assert_eq!(0, is_scanning(module));
+
+            // Powering the adapter off ends the scan
+            assert_eq!(
+                SUCCESS,
+                start_scan_for(module, null(), 60_000, on_completed, once(&tx))
+            );
+            assert_eq!(SUCCESS, mock_adapter_set_state(module, 2));
+            assert_eq!(ERROR_CANCELLED, wait(&rx));
+            assert_eq!(0, is_scanning(module));
+            free_module(module);
+        }
+    }
+
+    type Stopped = (*mut CModule, Sender<(c_int, c_int)>);
+
+    /// Checks the scan and tries to stop it from inside the callback
+    extern "C" fn stop_from_callback(_result: c_int, user_data: *mut c_void) {
+        let (module, tx) = *unsafe { Box::from_raw(user_data as *mut Stopped) };
+        let mut scanning = -1;
+        unsafe { module_is_scanning(module, &mut scanning) };
+        let _ = tx.send((scanning, unsafe { stop_scan_peripherals(module) }));
+    }
+
+    #[test]
+    fn failed_stops_leave_the_scan_running() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+            let stop_scan = MockOperation::StopScan as c_int;
+            assert_eq!(
+                SUCCESS,
+                mock_adapter_fail_next(module, stop_scan, ERROR_RUNTIME_ERROR)
+            );
+            assert_eq!(ERROR_RUNTIME_ERROR, stop_scan_peripherals(module));
+            assert_eq!(1, is_scanning(module));
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+            assert_eq!(0, is_scanning(module));
+
+            // So do scans whose timer fails to stop them
+            let (tx, rx) = channel();
+            assert_eq!(
+                SUCCESS,
+                mock_adapter_fail_next(module, stop_scan, ERROR_RUNTIME_ERROR)
+            );
+            assert_eq!(
+                SUCCESS,
+                start_scan_for(module, null(), 10, on_completed, once(&tx))
+            );
+            assert_eq!(ERROR_RUNTIME_ERROR, wait(&rx));
+            assert_eq!(1, is_scanning(module));
+            assert_eq!(SUCCESS, stop_scan_peripherals(module));
+            assert_eq!(0, is_scanning(module));
+            free_module(module);
+        }
+    }
+
+    #[test]
+    fn scans_are_not_stopped_from_callbacks() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            let (tx, rx) = channel();
+            let stopped = Box::into_raw(Box::new((module, tx))) as *mut c_void;
+            assert_eq!(
+                SUCCESS,
+                start_scan_for(module, null(), 10, stop_from_callback, stopped)
+            );
+            // Blocking on the runtime from its own thread would abort the process
+            assert_eq!((0, ERROR_RUNTIME_ERROR), wait(&rx));
+            free_module(module);
+        }
+    }
+}
@@ -0,0 +1,550 @@
+//! Blocking variants of the peripheral operations. Each one runs the same operation as its
+//! callback counterpart on the module runtime and returns the result code directly, recording
+//! failures in `peripheral_get_last_error` the same way.
+//!
+//! A `timeout_ms` of 0 waits indefinitely. These functions must not be called from inside a
+//! callback, as callbacks run on the runtime they would block.
+
+use crate::{
+    characteristic, connect, disconnect, discover_services, free_raw_slice, in_callback,
+    into_raw_slice, result_code, run, set_peripheral_error_str, subscribe, unsubscribe, write_type,
+    CPeripheral, Deadline, PeripheralHandle, ERROR_RUNTIME_ERROR, INVALID_ARGUMENT, IN_CALLBACK,
+    SUCCESS,
+};
+use btleplug::api::Descriptor;
+use log::{debug, error, info, trace};
+use std::ffi::c_int;
+use std::future::Future;
+use std::ptr::null_mut;
+use std::slice::from_raw_parts;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Runs `operation` to completion on the peripheral's runtime
+unsafe fn block_on<T, F>(
+    peripheral: *mut CPeripheral,
+    operation: impl FnOnce(Arc<PeripheralHandle>) -> F,
+) -> Result<T, c_int>
+where
+    F: Future<Output = Result<T, c_int>>,
+{
+    if peripheral.is_null() {
+        error!("null peripheral handle");
+        return Err(INVALID_ARGUMENT);
+    }
+
+    let m = &(*peripheral).module;
+
+    let Some(runtime) = m.runtime.as_ref() else {
+        error!("null runtime handle");
+        set_peripheral_error_str(&peripheral, "Invalid module");
+        return Err(INVALID_ARGUMENT);
+    };
+
+    if in_callback() {
+        set_peripheral_error_str(&peripheral, IN_CALLBACK);
+        return Err(ERROR_RUNTIME_ERROR);
+    }
+
+    runtime.block_on(operation((*peripheral).p.clone()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_is_connected_sync(
+    peripheral: *mut CPeripheral,
+    connected: *mut c_int,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_is_connected_sync");
+    if connected.is_null() {
+        error!("null connected");
+        return INVALID_ARGUMENT;
+    }
+    *connected = 0;
+
+    let result = block_on(peripheral, |ap| async move {
+        run(
+            &ap,
+            "is_connected",
+            Deadline::after(timeout_ms),
+            None,
+            ap.peripheral.is_connected(),
+        )
+        .await
+    });
+    if let Ok(v) = result {
+        debug!("Connected: {v}");
+        *connected = c_int::from(v);
+    }
+    result_code(&result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_connect_sync(
+    peripheral: *mut CPeripheral,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_connect_sync");
+    let result = block_on(peripheral, |ap| async move {
+        connect(&ap, Deadline::after(timeout_ms), None).await
+    });
+    result_code(&result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_disconnect_sync(
+    peripheral: *mut CPeripheral,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_disconnect_sync");
+    let result = block_on(peripheral, |ap| async move {
+        disconnect(&ap, Deadline::after(timeout_ms), None).await
+    });
+    result_code(&result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_discover_services_sync(
+    peripheral: *mut CPeripheral,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_discover_services_sync");
+    let result = block_on(peripheral, |ap| async move {
+        discover_services(&ap, Deadline::after(timeout_ms), None).await
+    });
+    result_code(&result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_subscribe_sync(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_subscribe_sync");
+    info!("Subscribing notification for {service_uuid}:{uuid}");
+    let result = block_on(peripheral, |ap| async move {
+        subscribe(&ap, service_uuid, uuid, Deadline::after(timeout_ms), None).await
+    });
+    result_code(&result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_unsubscribe_sync(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_unsubscribe_sync");
+    info!("Unsubscribing notification for {service_uuid}:{uuid}");
+    let result = block_on(peripheral, |ap| async move {
+        unsubscribe(&ap, service_uuid, uuid, Deadline::after(timeout_ms), None).await
+    });
+    result_code(&result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_write_sync(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    with_response: bool,
+    data: *const u8,
+    data_length: u32,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_write_sync");
+    if data.is_null() {
+        error!("null data");
+        if !peripheral.is_null() {
+            set_peripheral_error_str(&peripheral, "Null argument: data");
+        }
+        return INVALID_ARGUMENT;
+    }
+
+    info!("Writing {data_length} bytes to {service_uuid}:{uuid} (with_response: {with_response})");
+    let data = from_raw_parts(data, data_length as usize);
+    let result = block_on(peripheral, |ap| async move {
+        let characteristic = characteristic(service_uuid, uuid);
+        let write = ap
+            .peripheral
+            .write(&characteristic, data, write_type(with_response));
+        run(&ap, "write", Deadline::after(timeout_ms), None, write).await
+    });
+    result_code(&result)
+}
+
+/// On success `data` receives a buffer to release with `free_data`
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_read_sync(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+    data: *mut *mut u8,
+    data_length: *mut c_int,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_read_sync");
+    if data.is_null() || data_length.is_null() {
+        error!("null data");
+        return INVALID_ARGUMENT;
+    }
+    (*data, *data_length) = (null_mut(), 0);
+
+    info!("Reading from {service_uuid}:{uuid}");
+    let result = block_on(peripheral, |ap| async move {
+        let characteristic = characteristic(service_uuid, uuid);
+        run(
+            &ap,
+            "read",
+            Deadline::after(timeout_ms),
+            None,
+            ap.peripheral.read(&characteristic),
+        )
+        .await
+    });
+    match result {
+        Ok(value) => {
+            debug!("Read {} bytes", value.len());
+            (*data, *data_length) = into_raw_slice(value);
+            SUCCESS
+        }
+        Err(code) => code,
+    }
+}
+
+/// On success `data` receives a buffer to release with `free_data`
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_read_descriptor_sync(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    uuid: Uuid,
+    data: *mut *mut u8,
+    data_length: *mut c_int,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_read_descriptor_sync");
+    if data.is_null() || data_length.is_null() {
+        error!("null data");
+        return INVALID_ARGUMENT;
+    }
+    (*data, *data_length) = (null_mut(), 0);
+
+    info!("Reading descriptor {service_uuid}:{characteristic_uuid}:{uuid}");
+    let result = block_on(peripheral, |ap| async move {
+        let descriptor = Descriptor {
+            uuid,
+            service_uuid,
+            characteristic_uuid,
+        };
+        let read = ap.peripheral.read_descriptor(&descriptor);
+        run(
+            &ap,
+            "read_descriptor",
+            Deadline::after(timeout_ms),
+            None,
+            read,
+        )
+        .await
+    });
+    match result {
+        Ok(value) => {
+            debug!("Read {} bytes from descriptor", value.len());
+            (*data, *data_length) = into_raw_slice(value);
+            SUCCESS
+        }
+        Err(code) => code,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn peripheral_write_descriptor_sync(
+    peripheral: *mut CPeripheral,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    uuid: Uuid,
+    data: *const u8,
+    data_length: u32,
+    timeout_ms: u32,
+) -> c_int {
+    trace!("Enter: peripheral_write_descriptor_sync");
+    if data.is_null() {
+        error!("null data");
+        if !peripheral.is_null() {
+            set_peripheral_error_str(&peripheral, "Null argument: data");
+        }
+        return INVALID_ARGUMENT;
+    }
+
+    info!("Writing {data_length} bytes to descriptor {service_uuid}:{characteristic_uuid}:{uuid}");
+    let data = from_raw_parts(data, data_length as usize);
+    let result = block_on(peripheral, |ap| async move {
+        let descriptor = Descriptor {
+            uuid,
+            service_uuid,
+            characteristic_uuid,
+        };
+        let write = ap.peripheral.write_descriptor(&descriptor, data);
+        run(
+            &ap,
+            "write_descriptor",
+            Deadline::after(timeout_ms),
+            None,
+            write,
+        )
+        .await
+    });
+    result_code(&result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free_data(data: *mut u8, data_length: c_int) -> c_int {
+    if data.is_null() {
+        return SUCCESS;
+    }
+
+    free_raw_slice(data, data_length);
+    SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::peripheral_set_auto_resubscribe;
+    use crate::mock::tests::{leak, wait, Fixture, ADDRESS, CHARACTERISTIC, SERVICE};
+    use crate::mock::{
+        create_module_mock, mock_add_peripheral, mock_peripheral_add_descriptor,
+        mock_peripheral_set_latency,
+    };
+    use crate::{
+        free_module, peripheral_get_last_error, set_event_callbacks, start_scan_peripherals,
+        ERROR_NOT_CONNECTED, ERROR_NO_SUCH_CHARACTERISTIC, ERROR_TIMED_OUT,
+    };
+    use std::ffi::{c_void, CStr, CString};
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn sync_operations_return_results_directly() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(SUCCESS, peripheral_connect_sync(f.peripheral, 1000));
+            assert_eq!(
+                SUCCESS,
+                peripheral_discover_services_sync(f.peripheral, 1000)
+            );
+
+            let mut connected = 0;
+            assert_eq!(
+                SUCCESS,
+                peripheral_is_connected_sync(f.peripheral, &mut connected, 1000)
+            );
+            assert_eq!(1, connected);
+
+            let value = [7u8, 8];
+            assert_eq!(
+                SUCCESS,
+                peripheral_write_sync(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    true,
+                    value.as_ptr(),
+                    value.len() as u32,
+                    1000
+                )
+            );
+
+            let (mut data, mut data_length) = (null_mut(), 0);
+            assert_eq!(
+                SUCCESS,
+                peripheral_read_sync(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    &mut data,
+                    &mut data_length,
+                    1000
+                )
+            );
+            assert_eq!(&value, from_raw_parts(data, data_length as usize));
+            free_data(data, data_length);
+        }
+    }
+
+    #[test]
+    fn sync_descriptor_values_round_trip() {
+        unsafe {
+            let f = Fixture::new();
+            let descriptor = Uuid::from_u128(0x2902);
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_add_descriptor(
+                    f.module,
+                    ADDRESS,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    descriptor
+                )
+            );
+            f.connect();
+
+            let read = |uuid| {
+                let (mut data, mut data_length) = (null_mut(), 0);
+                let result = peripheral_read_descriptor_sync(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    uuid,
+                    &mut data,
+                    &mut data_length,
+                    1000,
+                );
+                let value = if data.is_null() {
+                    Vec::new()
+                } else {
+                    from_raw_parts(data, data_length as usize).to_vec()
+                };
+                free_data(data, data_length);
+                (result, value)
+            };
+            let write = |uuid, value: &[u8]| {
+                peripheral_write_descriptor_sync(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    uuid,
+                    value.as_ptr(),
+                    value.len() as u32,
+                    1000,
+                )
+            };
+
+            assert_eq!(SUCCESS, write(descriptor, &[1, 0]));
+            assert_eq!((SUCCESS, vec![1, 0]), read(descriptor));
+
+            let unknown = Uuid::from_u128(0x2903);
+            assert_eq!((ERROR_NO_SUCH_CHARACTERISTIC, Vec::new()), read(unknown));
+            assert_eq!(ERROR_NO_SUCH_CHARACTERISTIC, write(unknown, &[1]));
+        }
+    }
+
+    #[test]
+    fn sync_failures_are_recorded() {
+        unsafe {
+            let f = Fixture::new();
+            let (mut data, mut data_length) = (null_mut(), 0);
+            assert_eq!(
+                ERROR_NOT_CONNECTED,
+                peripheral_read_sync(
+                    f.peripheral,
+                    SERVICE,
+                    CHARACTERISTIC,
+                    &mut data,
+                    &mut data_length,
+                    0
+                )
+            );
+            assert!(data.is_null());
+            assert!(!CStr::from_ptr(peripheral_get_last_error(f.peripheral)).is_empty());
+        }
+    }
+
+    extern "C" fn connect_from_callback(
+        _id: u64,
+        peripheral: *mut CPeripheral,
+        _services: *const Uuid,
+        _service_count: c_int,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let results = unsafe { &*(user_data as *const Sender<(c_int, CString)>) };
+        unsafe {
+            let result = peripheral_connect_sync(peripheral, 0);
+            let error = CStr::from_ptr(peripheral_get_last_error(peripheral)).to_owned();
+            let _ = results.send((result, error));
+        }
+        0
+    }
+
+    extern "C" fn on_disconnected(_id: u64, _user_data: *mut c_void) {}
+
+    #[test]
+    fn sync_operations_fail_from_callbacks() {
+        unsafe {
+            let mut module = null_mut();
+            assert_eq!(SUCCESS, create_module_mock(&mut module));
+            assert_eq!(
+                SUCCESS,
+                mock_add_peripheral(module, ADDRESS, c"Sensor".as_ptr(), -40)
+            );
+            let (tx, rx) = channel::<(c_int, CString)>();
+            let results = leak(tx) as *const Sender<(c_int, CString)> as *mut c_void;
+            assert_eq!(
+                SUCCESS,
+                set_event_callbacks(module, connect_from_callback, on_disconnected, results)
+            );
+            assert_eq!(SUCCESS, start_scan_peripherals(module, null_mut(), 0));
+            let (result, error) = wait(&rx);
+            assert_eq!(ERROR_RUNTIME_ERROR, result);
+            assert_eq!(IN_CALLBACK, error.to_str().unwrap());
+            free_module(module);
+        }
+    }
+
+    #[test]
+    fn sync_operations_time_out() {
+        unsafe {
+            let f = Fixture::new();
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_latency(f.module, ADDRESS, 10_000)
+            );
+            assert_eq!(ERROR_TIMED_OUT, peripheral_connect_sync(f.peripheral, 10));
+            let error = CStr::from_ptr(peripheral_get_last_error(f.peripheral));
+            assert!(error.to_str().unwrap().contains("Timed out"));
+        }
+    }
+
+    #[test]
+    fn timeout_covers_every_step() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            assert_eq!(SUCCESS, peripheral_set_auto_resubscribe(f.peripheral, 1));
+            assert_eq!(SUCCESS, peripheral_disconnect_sync(f.peripheral, 0));
+
+            // Connecting, discovering services and resubscribing each fit, but not together
+            assert_eq!(SUCCESS, mock_peripheral_set_latency(f.module, ADDRESS, 100));
+            assert_eq!(ERROR_TIMED_OUT, peripheral_connect_sync(f.peripheral, 250));
+        }
+    }
+
+    #[test]
+    fn unsubscribe_timeout_covers_the_connection_check() {
+        unsafe {
+            let f = Fixture::new();
+            f.connect();
+            assert_eq!(
+                SUCCESS,
+                peripheral_subscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 0)
+            );
+            assert_eq!(
+                SUCCESS,
+                mock_peripheral_set_latency(f.module, ADDRESS, 10_000)
+            );
+            let start = Instant::now();
+            assert_eq!(
+                ERROR_TIMED_OUT,
+                peripheral_unsubscribe_sync(f.peripheral, SERVICE, CHARACTERISTIC, 50)
+            );
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+    }
+}